@@ -0,0 +1,406 @@
+//! Advertiser and publisher accounts shared by the DAP and SAP exchanges.
+//!
+//! An advertiser pays into its account ahead of time, and every issuance is
+//! debited from it: a DAP wallet costs its value and a SAP batch one unit per
+//! token. Issuance the balance can't cover is refused, and nothing signed is
+//! handed out. Each deposit and debit is kept as an entry, so the exchange
+//! can draw up a statement of an account over any period.
+//!
+//! On the other side, every accepted redemption is credited to the
+//! publisher that redeemed it. At the end of a billing period, the credits
+//! add up to a settlement of what each publisher is owed.
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::redemption::RedeemResponse;
+
+/// Error enum for the billing ledger.
+#[derive(Debug)]
+pub enum BillingError {
+    /// Thrown when no account is open for an advertiser.
+    UnknownAccount(u64),
+    /// Thrown when an account's balance cannot cover a debit of this amount.
+    InsufficientBalance(u64),
+}
+
+impl core::fmt::Display for BillingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BillingError::UnknownAccount(a) => write!(f, "No account for advertiser {}", a),
+            BillingError::InsufficientBalance(n) => write!(f, "Balance cannot cover {}", n),
+        }
+    }
+}
+
+impl std::error::Error for BillingError {}
+
+/// What an entry was for.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Item {
+    /// Funds paid in by the advertiser.
+    Deposit,
+    /// A DAP wallet worth this many leaves.
+    Wallet(u64),
+    /// A batch of this many SAP tokens.
+    Tokens(u64),
+}
+
+/// One line of an account's history.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Entry {
+    /// Seconds past the Unix epoch
+    pub time: u64,
+    pub item: Item,
+    /// Credited for a deposit, debited otherwise
+    pub amount: u64,
+    /// Balance after the entry
+    pub balance: u64,
+}
+
+/// A billing period, in seconds past the Unix epoch. The start is included
+/// and the end is not.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Period {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl Period {
+    /// Whether `time` falls within the period.
+    pub fn contains(&self, time: u64) -> bool {
+        time >= self.from && time < self.to
+    }
+}
+
+/// An account's entries over a period, with its balance either side.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Statement {
+    pub advertiser: u64,
+    #[serde(flatten)]
+    pub period: Period,
+    pub opening: u64,
+    pub closing: u64,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Default)]
+struct Account {
+    balance: u64,
+    entries: Vec<Entry>,
+}
+
+/// Advertiser accounts, keyed by advertiser ID. A ledger can be shared
+/// between threads and between the exchanges.
+#[derive(Default)]
+pub struct Ledger {
+    accounts: Mutex<BTreeMap<u64, Account>>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens an empty account for `advertiser`, unless it already has one.
+    pub fn open(&self, advertiser: u64) {
+        self.accounts.lock().entry(advertiser).or_default();
+    }
+
+    /// Credits `amount` to the account of `advertiser`. Returns the new
+    /// balance.
+    pub fn deposit(&self, advertiser: u64, amount: u64) -> Result<u64, BillingError> {
+        self.record(advertiser, Item::Deposit, amount)
+    }
+
+    /// Debits the cost of `item` from the account of `advertiser`, unless
+    /// the balance cannot cover it. Returns the new balance.
+    pub fn charge(&self, advertiser: u64, item: Item) -> Result<u64, BillingError> {
+        let amount = match item {
+            // Not a charge
+            Item::Deposit => return self.balance(advertiser),
+            Item::Wallet(value) => value,
+            Item::Tokens(count) => count,
+        };
+        self.record(advertiser, item, amount)
+    }
+
+    /// Returns the balance of the account of `advertiser`.
+    pub fn balance(&self, advertiser: u64) -> Result<u64, BillingError> {
+        self.accounts
+            .lock()
+            .get(&advertiser)
+            .map(|account| account.balance)
+            .ok_or(BillingError::UnknownAccount(advertiser))
+    }
+
+    /// Returns the entries of the account of `advertiser` made in `period`.
+    pub fn statement(&self, advertiser: u64, period: Period) -> Result<Statement, BillingError> {
+        let accounts = self.accounts.lock();
+        let account = accounts
+            .get(&advertiser)
+            .ok_or(BillingError::UnknownAccount(advertiser))?;
+        // Entries are kept in time order
+        let opening = account.entries
+            .iter()
+            .take_while(|entry| entry.time < period.from)
+            .last()
+            .map_or(0, |entry| entry.balance);
+        let entries: Vec<Entry> = account.entries
+            .iter()
+            .filter(|entry| period.contains(entry.time))
+            .cloned()
+            .collect();
+        let closing = entries.last().map_or(opening, |entry| entry.balance);
+        Ok(Statement { advertiser, period, opening, closing, entries })
+    }
+
+    fn record(&self, advertiser: u64, item: Item, amount: u64) -> Result<u64, BillingError> {
+        let mut accounts = self.accounts.lock();
+        let account = accounts
+            .get_mut(&advertiser)
+            .ok_or(BillingError::UnknownAccount(advertiser))?;
+        let balance = match item {
+            Item::Deposit => account.balance.saturating_add(amount),
+            _ => account.balance
+                .checked_sub(amount)
+                .ok_or(BillingError::InsufficientBalance(amount))?,
+        };
+        let time = stamp(account.entries.last().map(|entry| entry.time));
+        account.balance = balance;
+        account.entries.push(Entry { time, item, amount, balance });
+        Ok(balance)
+    }
+}
+
+/// The exchange a redemption went through.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Scheme {
+    Dap,
+    Sap,
+}
+
+/// An accepted redemption credited to a publisher.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Credit {
+    /// Seconds past the Unix epoch
+    pub time: u64,
+    pub scheme: Scheme,
+    /// Coins redeemed, and their total value
+    pub coins: u64,
+    pub value: u64,
+    /// Total credited to the publisher after this redemption
+    pub balance: u64,
+}
+
+/// What a publisher is owed for a billing period.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Payout {
+    pub publisher: u64,
+    pub redemptions: u64,
+    pub coins: u64,
+    pub value: u64,
+}
+
+/// What every publisher is owed for a billing period, by publisher ID.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Settlement {
+    #[serde(flatten)]
+    pub period: Period,
+    /// Publishers with nothing redeemed in the period are left out
+    pub payouts: Vec<Payout>,
+    pub total: u64,
+}
+
+impl Settlement {
+    /// Renders the settlement as CSV, one row per publisher. Every row
+    /// carries the period, so the reports of several periods can be
+    /// concatenated below one header.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("from,to,publisher,redemptions,coins,value\n");
+        for payout in self.payouts.iter() {
+            writeln!(csv,
+                     "{},{},{},{},{},{}",
+                     self.period.from,
+                     self.period.to,
+                     payout.publisher,
+                     payout.redemptions,
+                     payout.coins,
+                     payout.value).unwrap();
+        }
+        csv
+    }
+
+    /// Renders the settlement as JSON.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Publisher earnings, keyed by publisher ID. Publishers need no account
+/// opened: the first redemption credited to one starts its history.
+#[derive(Default)]
+pub struct Earnings {
+    publishers: Mutex<BTreeMap<u64, Vec<Credit>>>,
+}
+
+impl Earnings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Credits `publisher` with the value of the redemption settled by
+    /// `response`, if it was accepted. Returns the publisher's total.
+    pub fn credit(&self, publisher: u64, scheme: Scheme, response: &RedeemResponse) -> u64 {
+        let mut publishers = self.publishers.lock();
+        let credits = publishers.entry(publisher).or_default();
+        let total = credits.last().map_or(0, |credit| credit.balance);
+        if !response.valid || response.coins.is_empty() {
+            return total;
+        }
+        let balance = total.saturating_add(response.value);
+        let time = stamp(credits.last().map(|credit| credit.time));
+        credits.push(Credit {
+            time,
+            scheme,
+            coins: response.coins.len() as u64,
+            value: response.value,
+            balance,
+        });
+        balance
+    }
+
+    /// Returns the total ever credited to `publisher`.
+    pub fn balance(&self, publisher: u64) -> u64 {
+        self.publishers
+            .lock()
+            .get(&publisher)
+            .and_then(|credits| credits.last())
+            .map_or(0, |credit| credit.balance)
+    }
+
+    /// Returns the credits of `publisher`, oldest first.
+    pub fn history(&self, publisher: u64) -> Vec<Credit> {
+        self.publishers.lock().get(&publisher).cloned().unwrap_or_default()
+    }
+
+    /// Adds up the credits of every publisher made in `period`.
+    pub fn settlement(&self, period: Period) -> Settlement {
+        let payouts: Vec<Payout> = self.publishers
+            .lock()
+            .iter()
+            .filter_map(|(&publisher, credits)| {
+                let in_period: Vec<&Credit> = credits
+                    .iter()
+                    .filter(|credit| period.contains(credit.time))
+                    .collect();
+                if in_period.is_empty() {
+                    return None;
+                }
+                Some(Payout {
+                    publisher,
+                    redemptions: in_period.len() as u64,
+                    coins: in_period.iter().map(|credit| credit.coins).sum(),
+                    value: in_period.iter().map(|credit| credit.value).sum(),
+                })
+            })
+            .collect();
+        let total = payouts.iter().map(|payout| payout.value).sum();
+        Settlement { period, payouts, total }
+    }
+}
+
+// Seconds past the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Time of a new record, never earlier than the `last` one, so that records
+// stay in time order
+fn stamp(last: Option<u64>) -> u64 {
+    last.map_or(now(), |time| time.max(now()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::billing::*;
+    use crate::redemption::Rejection;
+
+    #[test]
+    fn ledger() {
+        let ledger = Ledger::new();
+        assert!(matches!(ledger.deposit(1, 10), Err(BillingError::UnknownAccount(1))));
+        ledger.open(1);
+        assert_eq!(ledger.deposit(1, 10).unwrap(), 10);
+        assert_eq!(ledger.charge(1, Item::Wallet(8)).unwrap(), 2);
+
+        // A charge the balance can't cover leaves it untouched
+        assert!(matches!(ledger.charge(1, Item::Tokens(3)), Err(BillingError::InsufficientBalance(3))));
+        assert_eq!(ledger.balance(1).unwrap(), 2);
+        // Opening an account again keeps its balance
+        ledger.open(1);
+        assert_eq!(ledger.charge(1, Item::Tokens(2)).unwrap(), 0);
+
+        let statement = ledger.statement(1, Period { from: 0, to: u64::MAX }).unwrap();
+        assert_eq!((statement.opening, statement.closing), (0, 0));
+        let items: Vec<(Item, u64, u64)> = statement.entries
+            .iter()
+            .map(|entry| (entry.item, entry.amount, entry.balance))
+            .collect();
+        assert_eq!(items,
+                   vec![(Item::Deposit, 10, 10),
+                        (Item::Wallet(8), 8, 2),
+                        (Item::Tokens(2), 2, 0)]);
+
+        // A period after every entry opens and closes on the last balance
+        ledger.deposit(1, 5).unwrap();
+        let statement = ledger.statement(1, Period { from: u64::MAX - 1, to: u64::MAX }).unwrap();
+        assert!(statement.entries.is_empty());
+        assert_eq!((statement.opening, statement.closing), (5, 5));
+    }
+
+    #[test]
+    fn settlement() {
+        let earnings = Earnings::new();
+        let accepted = RedeemResponse::settle(vec![Ok(4), Ok(1)]);
+        let rejected = RedeemResponse::settle(vec![Ok(4), Err(Rejection::BadProof)]);
+        assert_eq!(earnings.credit(2, Scheme::Dap, &accepted), 5);
+        // Only accepted redemptions are credited
+        assert_eq!(earnings.credit(2, Scheme::Dap, &rejected), 5);
+        assert_eq!(earnings.credit(1, Scheme::Sap, &RedeemResponse::settle(vec![Ok(1)])), 1);
+        assert_eq!(earnings.credit(2, Scheme::Sap, &accepted), 10);
+        assert_eq!(earnings.balance(2), 10);
+        assert_eq!(earnings.balance(3), 0);
+        let history: Vec<(Scheme, u64, u64)> = earnings.history(2)
+            .iter()
+            .map(|credit| (credit.scheme, credit.coins, credit.value))
+            .collect();
+        assert_eq!(history, vec![(Scheme::Dap, 2, 5), (Scheme::Sap, 2, 5)]);
+
+        let settlement = earnings.settlement(Period { from: 0, to: u64::MAX });
+        assert_eq!(settlement.payouts,
+                   vec![Payout { publisher: 1, redemptions: 1, coins: 1, value: 1 },
+                        Payout { publisher: 2, redemptions: 2, coins: 4, value: 10 }]);
+        assert_eq!(settlement.total, 11);
+        assert_eq!(settlement.to_csv(),
+                   format!("from,to,publisher,redemptions,coins,value\n\
+                            0,{0},1,1,1,1\n\
+                            0,{0},2,2,4,10\n", u64::MAX));
+        let parsed: Settlement = serde_json::from_str(&settlement.to_json().unwrap()).unwrap();
+        assert_eq!(parsed.payouts, settlement.payouts);
+        assert_eq!(parsed.total, 11);
+        assert_eq!(parsed.period, settlement.period);
+
+        // A period without credits pays out nothing
+        let settlement = earnings.settlement(Period { from: 0, to: 1 });
+        assert!(settlement.payouts.is_empty());
+        assert_eq!(settlement.total, 0);
+        assert_eq!(settlement.to_csv(), "from,to,publisher,redemptions,coins,value\n");
+    }
+}
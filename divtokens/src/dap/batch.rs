@@ -0,0 +1,77 @@
+//! Batch verification of the Groth16 proofs in a redeem request.
+//!
+//! Each proof `i` satisfies
+//!
+//!     e(A_i, B_i) = e(alpha, beta) e(IC_i, gamma) e(C_i, delta)
+//!
+//! where `IC_i` is the proof's prepared public inputs. Raising each equation
+//! to a random 128-bit `r_i` and multiplying them together gives one check
+//! that shares the `gamma` and `delta` pairings between every proof. A batch
+//! containing an invalid proof passes with probability at most 2^-128.
+use ark_bls12_381::{Bls12_381, Fr, G1Projective};
+use ark_ec::{AffineCurve, PairingEngine, ProjectiveCurve};
+use ark_ff::{Field, PrimeField, Zero};
+use ark_groth16::{prepare_inputs, verify_proof_with_prepared_inputs};
+use ark_relations::r1cs::SynthesisError;
+use ark_std::rand::{CryptoRng, Rng};
+
+use crate::dap::types::*;
+
+/// A proof and the public inputs it claims.
+#[derive(Clone)]
+pub struct Claim {
+    pub inputs: Vec<Fp>,
+    pub proof: GrothProof,
+}
+
+/// Verifies every claim against `pvk`. Returns the indices of the invalid
+/// claims.
+///
+/// The claims are checked together; each is only checked on its own if the
+/// batch fails, to find which ones are at fault.
+pub fn verify<R: Rng + CryptoRng>(pvk: &GrothPreparedVerifyingKey,
+                                  claims: &[Claim],
+                                  rng: &mut R)
+                                  -> Result<Vec<usize>, SynthesisError> {
+    if claims.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut pairs: Vec<(<Bls12_381 as PairingEngine>::G1Prepared,
+                        <Bls12_381 as PairingEngine>::G2Prepared)>
+        = Vec::with_capacity(claims.len() + 2);
+    let mut prepared = Vec::with_capacity(claims.len());
+    // Sums of r_i IC_i and r_i C_i, and of the r_i
+    let mut sum_ic = G1Projective::zero();
+    let mut sum_c = G1Projective::zero();
+    let mut sum_r = Fr::zero();
+    for claim in claims {
+        let g_ic = prepare_inputs(pvk, &claim.inputs)?;
+        let r = Fr::from(rng.gen::<u128>());
+
+        pairs.push((claim.proof.a.mul(r.into_repr()).into_affine().into(), claim.proof.b.into()));
+        sum_ic += g_ic.mul(r.into_repr());
+        sum_c += claim.proof.c.mul(r.into_repr());
+        sum_r += r;
+        prepared.push(g_ic);
+    }
+    pairs.push((sum_ic.into_affine().into(), pvk.gamma_g2_neg_pc.clone()));
+    pairs.push((sum_c.into_affine().into(), pvk.delta_g2_neg_pc.clone()));
+    let expected = pvk.alpha_g1_beta_g2.pow(sum_r.into_repr());
+
+    let qap = Bls12_381::miller_loop(pairs.iter());
+    let test = Bls12_381::final_exponentiation(&qap)
+        .ok_or(SynthesisError::UnexpectedIdentity)?;
+    if test == expected {
+        return Ok(vec![]);
+    }
+
+    // Find the culprits
+    let mut invalid = vec![];
+    for (i, claim) in claims.iter().enumerate() {
+        if !verify_proof_with_prepared_inputs(pvk, &claim.proof, &prepared[i])? {
+            invalid.push(i);
+        }
+    }
+    Ok(invalid)
+}
@@ -1,7 +1,30 @@
+//! The spend circuit, proving that a node of a wallet issued by the exchange
+//! is being spent.
+//!
+//! One circuit serves nodes at every depth of a height-`N` wallet, with the
+//! depth a public input. The node's path to the root is padded to `N` levels
+//! from the bottom, and a selector bit per level switches the padding off:
+//! `active[i]` is set for the top `depth` levels only, and the running hash
+//! passes through the other levels unchanged.
+//!
+//! In nullifier mode the node, its position and the wallet tag stay private.
+//! The circuit instead outputs the `nullifiers` of the node and of each of
+//! its ancestors, which the exchange indexes spends by.
+//!
+//! A circuit with several slots spends as many nodes of one wallet, checking
+//! the issuer's signature and the commitment opening only once. Its public
+//! inputs are the issuer key, the context and, unless nullified, the wallet
+//! tag, followed by each slot's node, position and depth or its depth and
+//! nullifiers. Slots left over are filled by repeating a node.
+//!
+//! The issuer signs the commitment together with the wallet's value, the
+//! number of leaves it was paid for. Only nodes whose leaves all fall below
+//! the value can be spent, so a larger tree is worth no more than was paid.
 use ark_ec::ProjectiveCurve;
 use ark_ff::PrimeField;
+use ark_crypto_primitives::Error;
 use ark_r1cs_std::{
-    alloc::AllocVar,
+    alloc::{AllocVar, AllocationMode},
     prelude::*,
     fields::fp::FpVar,
 };
@@ -9,12 +32,8 @@ use ark_relations::r1cs::{
     ConstraintSynthesizer,
     ConstraintSystemRef,
     SynthesisError};
-//use crate::merkle_tree::Path;
-use arkworks_native_gadgets::merkle_tree::Path;
-use arkworks_r1cs_gadgets::{
-    merkle_tree::PathVar,
-    poseidon::FieldHasherGadget,
-};
+use arkworks_native_gadgets::poseidon::FieldHasher;
+use arkworks_r1cs_gadgets::poseidon::FieldHasherGadget;
 use core::{borrow::Borrow, marker::PhantomData};
 
 use crate::schnorr::{
@@ -25,6 +44,56 @@ use crate::schnorr::{
 };
 use crate::schnorr::constraints::SigVerifyGadget;
 
+/// Returns the nullifiers of the node at `depth` with position `pos` in the
+/// tree of `root` and of each of its ancestors, root first. The node at depth
+/// `j` with position `p` gets `H(root, 2^j + p)`, hashing its index in level
+/// order, so nullifiers can't be told apart without the root.
+pub fn nullifiers<F: PrimeField, H: FieldHasher<F>>(hasher: &H,
+                                                   root: F,
+                                                   depth: usize,
+                                                   pos: u64)
+                                                   -> Result<Vec<F>, Error> {
+    (0..=depth)
+        .map(|j| hasher.hash(&[root, F::from((1u64 << j) + (pos >> (depth - j)))]))
+        .collect()
+}
+
+/// A node spent in the circuit, at `depth` and position `pos` in its level.
+#[derive(Clone)]
+pub struct Slot<F: PrimeField, const N: usize> {
+    pub node: F,
+    pub depth: usize,
+    pub pos: u64,
+    // Siblings on the node's path to the root, lowest level first and
+    // padded to N levels from the bottom
+    pub siblings: [F; N],
+}
+
+impl<F: PrimeField, const N: usize> Slot<F, N> {
+    /// Builds the slot of `node` from its `(left, right)` path pairs as given
+    /// by `MerkleTree::path`.
+    pub fn new(node: F, depth: usize, pos: u64, path: &[(F, F)]) -> Self {
+        assert!(depth <= N, "Depth {} exceeds the wallet height {}", depth, N);
+        assert_eq!(path.len(), depth, "Expected a path of length {}", depth);
+        let mut siblings = [F::zero(); N];
+        for (j, (left, right)) in path.iter().enumerate() {
+            // The ancestor at this level is a right child iff its position
+            // is odd
+            siblings[N - depth + j] = if (pos >> j) & 1 == 1 { *left } else { *right };
+        }
+        Self { node, depth, pos, siblings }
+    }
+}
+
+/// Returns the message the issuer signs for a wallet committed to by `com`
+/// and worth `value` leaves.
+pub fn signed_message<F: PrimeField, H: FieldHasher<F>>(hasher: &H,
+                                                        com: F,
+                                                        value: u64)
+                                                        -> Result<F, Error> {
+    hasher.hash(&[com, F::from(value)])
+}
+
 // TODO: #[derive(Copy)]
 pub struct SpendCircuit<F: PrimeField,
                     C: ProjectiveCurve,
@@ -38,10 +107,15 @@ pub struct SpendCircuit<F: PrimeField,
     pub root: F,
     pub com: F,
     pub open: F,
-    pub leaf: F,
-    pub path: Path<F, HG::Native, N>,
+    // Leaves the wallet was issued for, signed with com
+    pub value: u64,
+    pub ctx: F,
+    // Nodes spent under the one signature, all of the same wallet
+    pub slots: Vec<Slot<F, N>>,
     pub hasher: HG::Native,
-    _sig_scheme: PhantomData<S>,    
+    // Whether to hide the nodes behind nullifiers
+    pub nullify: bool,
+    _sig_scheme: PhantomData<S>,
     _sig_gadget: PhantomData<SG>,
 }
 
@@ -52,25 +126,64 @@ impl<F: PrimeField,
      SG: SigVerifyGadget<S, F>,
      HG: FieldHasherGadget<F>,
      const N: usize> SpendCircuit<F, C, S, SG, HG, N> {
+    /// Builds the circuit for `node`, at `depth` and position `pos` in its
+    /// level, from its `(left, right)` path pairs as given by
+    /// `MerkleTree::path`, revealing the node or, if `nullify` is set, only
+    /// its nullifiers.
     pub fn new(params: Parameters<C>,
                pk: PublicKey<C>,
                sig: Signature<C>,
                root: F,
                com: F,
                open: F,
-               leaf: F,
-               path: Path<F, HG::Native, N>,
-               hasher: HG::Native)
+               value: u64,
+               node: F,
+               depth: usize,
+               pos: u64,
+               ctx: F,
+               path: &[(F, F)],
+               hasher: HG::Native,
+               nullify: bool)
                -> Self {
+        Self::with_slots(params,
+                         pk,
+                         sig,
+                         root,
+                         com,
+                         open,
+                         value,
+                         ctx,
+                         vec![Slot::new(node, depth, pos, path)],
+                         hasher,
+                         nullify)
+    }
+
+    /// Builds the circuit for several nodes of the wallet of `root`, one per
+    /// slot. The signature and commitment are checked once for all of them.
+    pub fn with_slots(params: Parameters<C>,
+                      pk: PublicKey<C>,
+                      sig: Signature<C>,
+                      root: F,
+                      com: F,
+                      open: F,
+                      value: u64,
+                      ctx: F,
+                      slots: Vec<Slot<F, N>>,
+                      hasher: HG::Native,
+                      nullify: bool)
+                      -> Self {
+        assert!(!slots.is_empty(), "Expected at least one slot");
 	Self { params,
                pk,
                sig,
                root,
                com,
                open,
-               leaf,
-               path,
+               value,
+               ctx,
+               slots,
                hasher,
+               nullify,
                _sig_scheme: PhantomData,
                _sig_gadget: PhantomData }
     }
@@ -92,14 +205,40 @@ where
     fn generate_constraints(self,
                             cs: ConstraintSystemRef<F>)
                             -> Result<(), SynthesisError> {
+        // Wallet tag H(root), exposed with the nodes' positions so the
+        // exchange can index spent nodes by wallet and path prefix
+        let tag = self.hasher
+            .hash(&[self.root])
+            .map_err(|_| SynthesisError::Unsatisfiable)?;
+        // Nullifiers of each node and its ancestors, padded with zeros to
+        // one per level
+        let mut slot_nfs = Vec::with_capacity(self.slots.len());
+        for slot in self.slots.iter() {
+            let mut nfs = vec![F::zero(); N + 1];
+            if self.nullify {
+                let node_nfs = nullifiers(&self.hasher, self.root, slot.depth, slot.pos)
+                    .map_err(|_| SynthesisError::Unsatisfiable)?;
+                nfs[..node_nfs.len()].copy_from_slice(&node_nfs);
+            }
+            slot_nfs.push(nfs);
+        }
+        // The nodes and where they sit are only public without nullifiers
+        let reveal = if self.nullify {
+            AllocationMode::Witness
+        } else {
+            AllocationMode::Input
+        };
+
         let params_var = SG::ParametersVar::new_constant(
             cs.clone(),
             self.params)
             .unwrap();
-        let pk_var = SG::PublicKeyVar::new_constant(
+        // An input rather than a constant, so one setup serves every
+        // issuer key
+        let pk_var = SG::PublicKeyVar::new_input(
             cs.clone(),
-            self.pk)
-            .unwrap();        
+            || Ok(self.pk))
+            .unwrap();
         let sig_var = SG::SignatureVar::new_witness(
             cs.clone(),
             || Ok(&self.sig))
@@ -111,29 +250,40 @@ where
         let com_var = FpVar::<F>::new_witness(
             cs.clone(),
             || Ok(self.com))
-            .unwrap();        
+            .unwrap();
         let open_var = FpVar::<F>::new_witness(
             cs.clone(),
             || Ok(self.open))
             .unwrap();
-	let leaf_var = FpVar::<F>::new_input(
+        let value_var = FpVar::<F>::new_witness(
             cs.clone(),
-            || Ok(self.leaf))
+            || Ok(F::from(self.value)))
             .unwrap();
-	let path_var = PathVar::<F, HG, N>::new_witness(
+        let ctx_var = FpVar::<F>::new_input(
             cs.clone(),
-            || Ok(self.path))
+            || Ok(self.ctx))
             .unwrap();
+        let tag_var = if self.nullify {
+            None
+        } else {
+            Some(FpVar::<F>::new_input(
+                cs.clone(),
+                || Ok(tag))
+                .unwrap())
+        };
         let hasher_gadget = HG::from_native(
             &mut cs.clone(),
             self.hasher)
             .unwrap();
 
-        // Check sig is a valid signature of com under pk
-        // sig.verify(pk, sig, com) = 1
+        // Check sig is a valid signature of com and value under pk
+        // sig.verify(pk, sig, H(com, value)) = 1
+        let msg_var = hasher_gadget
+            .hash(&[com_var.clone(), value_var.clone()])
+            .unwrap();
         SG::verify(&params_var,
                    &pk_var,
-                   &com_var,
+                   &msg_var,
                    &sig_var)
             .unwrap()
             .enforce_equal(&Boolean::<F>::TRUE)
@@ -147,130 +297,177 @@ where
             .enforce_equal(&com_var)
             .unwrap();
 
-        // Check leaf in the Merkle tree of root
-        path_var
-	    .check_membership(&root_var, &leaf_var, &hasher_gadget)
-	    .unwrap()
-            .enforce_equal(&Boolean::<F>::TRUE)
-            .unwrap();
+        // Check tag identifies root
+        // H(root) = tag
+        if let Some(tag_var) = tag_var {
+            hasher_gadget
+                .hash(&[root_var.clone()])
+                .unwrap()
+                .enforce_equal(&tag_var)
+                .unwrap();
+        }
 
-        //println!("Spend constraints: {:?}", cs.num_constraints());        
-	Ok(())
-    }
-}
+        // Check each node is in the tree of root
+        for (slot, nfs) in self.slots.into_iter().zip(slot_nfs) {
+            // Padding levels below the node, and the position of its
+            // leftmost leaf, whose bits steer the path
+            let shift = N - slot.depth;
+            let leaf_pos = slot.pos << shift;
 
-// TODO: #[derive(Copy)]
-pub struct RootCircuit<F: PrimeField,
-                    C: ProjectiveCurve,
-                    S: SignatureScheme,
-                    SG: SigVerifyGadget<S, F>,
-                    HG: FieldHasherGadget<F>,
-                    const N: usize> {
-    pub params: Parameters<C>,
-    pub pk: PublicKey<C>,
-    pub sig: Signature<C>,
-    pub root: F,
-    pub com: F,
-    pub open: F,
-    pub hasher: HG::Native,
-    _sig_scheme: PhantomData<S>,    
-    _sig_gadget: PhantomData<SG>,
-}
+            let node_var = FpVar::<F>::new_variable(
+                cs.clone(),
+                || Ok(slot.node),
+                reveal)
+                .unwrap();
+            let pos_var = FpVar::<F>::new_variable(
+                cs.clone(),
+                || Ok(F::from(slot.pos)),
+                reveal)
+                .unwrap();
+            let depth_var = FpVar::<F>::new_input(
+                cs.clone(),
+                || Ok(F::from(slot.depth as u64)))
+                .unwrap();
+            let mut nf_vars = vec![];
+            if self.nullify {
+                for nf in nfs {
+                    nf_vars.push(FpVar::<F>::new_input(
+                        cs.clone(),
+                        || Ok(nf))
+                        .unwrap());
+                }
+            }
+            let mut active = Vec::with_capacity(N);
+            let mut bits = Vec::with_capacity(N);
+            let mut sibling_vars = Vec::with_capacity(N);
+            for i in 0..N {
+                active.push(Boolean::new_witness(
+                    cs.clone(),
+                    || Ok(i >= shift))
+                    .unwrap());
+                bits.push(Boolean::new_witness(
+                    cs.clone(),
+                    || Ok((leaf_pos >> i) & 1 == 1))
+                    .unwrap());
+                sibling_vars.push(FpVar::<F>::new_witness(
+                    cs.clone(),
+                    || Ok(slot.siblings[i]))
+                    .unwrap());
+            }
 
-#[allow(dead_code)]
-impl<F: PrimeField,
-     C: ProjectiveCurve,
-     S: SignatureScheme,
-     SG: SigVerifyGadget<S, F>,
-     HG: FieldHasherGadget<F>,
-     const N: usize> RootCircuit<F, C, S, SG, HG, N> {
-    pub fn new(params: Parameters<C>,
-               pk: PublicKey<C>,
-               sig: Signature<C>,
-               root: F,
-               com: F,
-               open: F,
-               _leaf: F,
-               _path: Path<F, HG::Native, N>,
-               hasher: HG::Native)
-               -> Self {
-	Self { params,
-               pk,
-               sig,
-               root,
-               com,
-               open,
-               hasher,
-               _sig_scheme: PhantomData,
-               _sig_gadget: PhantomData }
-    }
-}
+            // Check the top depth levels are the active ones
+            // active[i] => active[i + 1], sum(active) = depth
+            let mut num_active = FpVar::<F>::zero();
+            for i in 0..N {
+                if i + 1 < N {
+                    active[i]
+                        .and(&active[i + 1].not())
+                        .unwrap()
+                        .enforce_equal(&Boolean::<F>::FALSE)
+                        .unwrap();
+                }
+                num_active += FpVar::<F>::from(active[i].clone());
+            }
+            num_active
+                .enforce_equal(&depth_var)
+                .unwrap();
 
-impl<F: PrimeField,
-     C: ProjectiveCurve,
-     S: SignatureScheme,
-     SG: SigVerifyGadget<S, F>,
-     HG: FieldHasherGadget<F>,
-     const N: usize>
-    ConstraintSynthesizer<F> for RootCircuit<F, C, S, SG, HG, N>
-where
-    // TODO: Clean up this mess
-    Parameters<C>: Borrow<<S as SignatureScheme>::Parameters>,
-    for<'a> &'a Signature<C>: Borrow<<S as SignatureScheme>::Signature>,
-    <C as ProjectiveCurve>::Affine: Borrow<<S as SignatureScheme>::PublicKey>
-{
-    fn generate_constraints(self,
-                            cs: ConstraintSystemRef<F>)
-                            -> Result<(), SynthesisError> {
-        let params_var = SG::ParametersVar::new_constant(
-            cs.clone(),
-            self.params)
-            .unwrap();
-        let pk_var = SG::PublicKeyVar::new_constant(
-            cs.clone(),
-            self.pk)
-            .unwrap();        
-        let sig_var = SG::SignatureVar::new_witness(
-            cs.clone(),
-            || Ok(&self.sig))
-            .unwrap();
-	let root_var = FpVar::<F>::new_input(
-            cs.clone(),
-            || Ok(self.root))
-            .unwrap();
-        let com_var = FpVar::<F>::new_witness(
-            cs.clone(),
-            || Ok(self.com))
-            .unwrap();        
-        let open_var = FpVar::<F>::new_witness(
-            cs.clone(),
-            || Ok(self.open))
-            .unwrap();
-        let hasher_gadget = HG::from_native(
-            &mut cs.clone(),
-            self.hasher)
-            .unwrap();
+            // Check the path bits give the node's position, shifted past the
+            // padding levels
+            // bits[i] => active[i], sum(bits[i] 2^i) = pos 2^(N - depth)
+            let mut bits_pos = FpVar::<F>::zero();
+            let mut scale = FpVar::<F>::one();
+            let mut power = F::one();
+            for i in 0..N {
+                bits[i]
+                    .and(&active[i].not())
+                    .unwrap()
+                    .enforce_equal(&Boolean::<F>::FALSE)
+                    .unwrap();
+                bits_pos += FpVar::<F>::from(bits[i].clone()) * power;
+                power.double_in_place();
+                scale *= active[i]
+                    .select(&FpVar::<F>::one(), &FpVar::<F>::constant(F::from(2u64)))
+                    .unwrap();
+            }
+            bits_pos
+                .enforce_equal(&(pos_var * scale.clone()))
+                .unwrap();
 
-        // Check sig is a valid signature of com under pk
-        // sig.verify(pk, sig, com) = 1
-        SG::verify(&params_var,
-                   &pk_var,
-                   &com_var,
-                   &sig_var)
-            .unwrap()
-            .enforce_equal(&Boolean::<F>::TRUE)
-            .unwrap();
+            // Check the node's leaves fall below the wallet's value, as the
+            // slack after its last leaf fits in N + 1 bits
+            // pos 2^(N - depth) + 2^(N - depth) + slack = value
+            let slack = self.value.wrapping_sub((slot.pos + 1) << shift);
+            let mut slack_var = FpVar::<F>::zero();
+            let mut power = F::one();
+            for i in 0..=N {
+                let bit = Boolean::new_witness(
+                    cs.clone(),
+                    || Ok((slack >> i) & 1 == 1))
+                    .unwrap();
+                slack_var += FpVar::<F>::from(bit) * power;
+                power.double_in_place();
+            }
+            (bits_pos + scale + slack_var)
+                .enforce_equal(&value_var)
+                .unwrap();
 
-        // Check com opens to root
-        // verifycom(root, com, open) = 1, i.e., H(root, open) = com
-        hasher_gadget
-            .hash(&[root_var.clone(), open_var])
-            .unwrap()
-            .enforce_equal(&com_var)
+            // Check node in the Merkle tree of root, hashing only at the
+            // active levels
+            let mut cur = node_var;
+            for i in 0..N {
+                let left = bits[i]
+                    .select(&sibling_vars[i], &cur)
+                    .unwrap();
+                let right = bits[i]
+                    .select(&cur, &sibling_vars[i])
+                    .unwrap();
+                let parent = hasher_gadget
+                    .hash_two(&left, &right)
+                    .unwrap();
+                cur = active[i]
+                    .select(&parent, &cur)
+                    .unwrap();
+            }
+            cur
+                .enforce_equal(&root_var)
+                .unwrap();
+
+            // Check the nullifiers of the node's ancestors and itself, and
+            // that the ones below it are zero
+            // nf[j] = H(root, 2^j + (pos 2^(N - depth) >> (N - j))) for j <= depth
+            for (j, nf_var) in nf_vars.iter().enumerate() {
+                let mut index = FpVar::<F>::constant(F::from(1u64 << j));
+                let mut power = F::one();
+                for bit in bits[N - j..].iter() {
+                    index += FpVar::<F>::from(bit.clone()) * power;
+                    power.double_in_place();
+                }
+                let nf = hasher_gadget
+                    .hash(&[root_var.clone(), index])
+                    .unwrap();
+                // The root is an ancestor of every node, and the node at
+                // depth j > 0 is one iff level N - j is active
+                let is_ancestor = if j == 0 {
+                    Boolean::<F>::TRUE
+                } else {
+                    active[N - j].clone()
+                };
+                is_ancestor
+                    .select(&nf, &FpVar::<F>::zero())
+                    .unwrap()
+                    .enforce_equal(nf_var)
+                    .unwrap();
+            }
+        }
+
+        // Bind the redemption context to the proof, so the coins can't be
+        // redeemed under another one
+        ctx_var
+            .square()
             .unwrap();
 
-        //println!("Spend constraints: {:?}", cs.num_constraints());        
+        //println!("Spend constraints: {:?}", cs.num_constraints());
 	Ok(())
     }
 }
-
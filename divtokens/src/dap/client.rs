@@ -1,6 +1,5 @@
 //use aes::cipher::generic_array::{GenericArray, typenum::U16, typenum::U32};
 use ark_bls12_381::Fr;
-use ark_crypto_primitives::SNARK;
 use ark_ed_on_bls12_381::EdwardsProjective as JubJub;
 use ark_ff::{
     Fp256,
@@ -8,163 +7,512 @@ use ark_ff::{
     UniformRand
 };
 use ark_serialize::*;
-use ark_std::test_rng;
+use ark_std::rand::{CryptoRng, Rng};
 use arkworks_native_gadgets::poseidon::FieldHasher;
-use rand::Rng;
+use bit_vec::BitVec;
+use rand_core::OsRng;
+use std::collections::BTreeMap;
 
 
 use crate::dap::{
+    circuit,
+    dispatch,
+    error::DapError,
     messages::{
         IssueRequest,
         IssueResponse,
         RedeemRequest,
     },
+    tree::{MerkleTree, node_at, node_index},
     types::*,
 };
-use crate::ggm::GGM;
+use crate::ggm::{GGM, u16_to_bv};
 use crate::schnorr::{Signature, SignatureScheme};
 
 
-#[macro_export]
-macro_rules! mk_client {
-    ($wallet: ident, $smt: ident, $client: ident, $height: ident, $circ: ident) => {    
-        pub struct $wallet {
-            pub key: [u8; 32],
-            pub leaves: Vec<Fp>,
-            pub smt: $smt,
-            pub root: Fp,
-            pub com: Fp,
-            pub open: Fp,
-            pub sig: Option<Signature::<JubJub>>,
-            pub coins: Vec<Coin>,
+/// Covers `n` with subtree nodes drawn from the wallets whose spent leaves
+/// are given in `spent`. Denominations are taken largest first, each from the
+/// leftmost fully-unspent node of the first wallet that has one. Returns the
+/// chosen nodes as `(wallet, depth, pos)` triples, or `None` if the wallets
+/// cannot cover `n` between them.
+pub fn allocate(spent: &[BitVec], height: usize, n: u16) -> Option<Vec<(usize, usize, u64)>> {
+    let mut spent = spent.to_vec();
+    let mut remaining = n as u64;
+    let mut nodes = vec![];
+    // Largest denominations first to keep the free space aligned
+    for bit in (0..=height).rev() {
+        let depth = height - bit;
+        let width = 1usize << bit;
+        while remaining >= width as u64 {
+            let found = spent.iter().enumerate().find_map(|(w, leaves)| {
+                (0..(1usize << depth))
+                    .find(|p| (p * width..(p + 1) * width).all(|i| !leaves[i]))
+                    .map(|p| (w, p))
+            });
+            let (w, pos) = match found {
+                Some(node) => node,
+                None => break,
+            };
+            for i in pos * width..(pos + 1) * width {
+                spent[w].set(i, true);
+            }
+            nodes.push((w, depth, pos as u64));
+            remaining -= width as u64;
         }
+    }
+    if remaining > 0 {
+        return None;
+    }
+    Some(nodes)
+}
+
+// Whether none of the leaves under the node at `depth` with position `pos`
+// has been spent
+fn unspent(spent: &BitVec, height: usize, depth: usize, pos: u64) -> bool {
+    let width = 1usize << (height - depth);
+    let start = (pos as usize) * width;
+    (start..start + width).all(|i| !spent[i])
+}
 
-        pub struct $client {
-            pub pp: PP,
-            pub wallet: Vec<$wallet>,
-            pub coins: Vec<Coin>
+pub struct Entry {
+    // Issuance request ID, echoed back in the issue response
+    pub id: u64,
+    pub key: [u8; 32],
+    pub leaves: Vec<Fp>,
+    pub tree: MerkleTree,
+    pub root: Fp,
+    // Identifies the wallet's spent nodes at the exchange
+    pub tag: Fp,
+    // Epoch of the key that signed, once issued
+    pub epoch: u64,
+    pub com: Fp,
+    pub open: Fp,
+    // Leaves the wallet was paid for, signed with com
+    pub value: u64,
+    pub sig: Option<Signature::<JubJub>>,
+    // Precomputed proofs and the context each is bound to, keyed by Merkle
+    // tree node index
+    pub proofs: BTreeMap<u64, (Context, Vec<u8>)>,
+    pub spent: BitVec,
+}
+
+pub struct Client {
+    // Parameters of the current epoch, and of earlier ones by epoch
+    pub pp: PP,
+    pub past: BTreeMap<u64, PP>,
+    pub wallet: Vec<Entry>,
+    pub coins: Vec<Coin>,
+    pub next_id: u64,
+}
+
+impl Client {
+    pub fn new(pp: PP) -> Client {
+        Client { pp, past: BTreeMap::new(), wallet: vec![], coins: vec![], next_id: 0 }
+    }
+
+    /// Moves to the parameters of a new epoch, keeping the current ones for
+    /// wallets issued under them.
+    pub fn update(&mut self, pp: PP) {
+        let old = std::mem::replace(&mut self.pp, pp);
+        self.past.insert(old.epoch, old);
+    }
+
+    /// Forgets the parameters of epochs before `epoch`, and the wallets
+    /// issued under them, once the exchange no longer redeems them.
+    pub fn expire(&mut self, epoch: u64) {
+        self.past.retain(|&e, _| e >= epoch);
+        self.wallet.retain(|entry| entry.sig.is_none() || entry.epoch >= epoch);
+    }
+
+    fn pp_at(&self, epoch: u64) -> Result<&PP, DapError> {
+        if self.pp.epoch == epoch {
+            return Ok(&self.pp);
         }
+        self.past.get(&epoch).ok_or(DapError::UnknownEpoch(epoch))
+    }
 
-        impl $client {
-            pub fn new(pp: PP) -> $client {
-                $client { pp, wallet: vec![], coins: vec![] }
-            }
-            
-            pub fn issue_request(&mut self) -> IssueRequest {
-                let rng = &mut test_rng();
-                
-                // Generate master key
-                let key = rand::thread_rng().gen::<[u8; 32]>();
-                
-                // Compute GGM-tree leaves
-                let ggm = GGM::new();
-                let leaves_bytes = ggm.expand(&key, ($height).try_into().unwrap());
-                let mut leaves = Vec::new();
-                for bytes in leaves_bytes {
-                    leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
+    /// Returns the unspent value across all issued wallets.
+    pub fn balance(&self) -> u64 {
+        self.wallet
+            .iter()
+            .filter(|entry| entry.sig.is_some())
+            .map(|entry| entry.spent.iter().filter(|spent| !spent).count() as u64)
+            .sum()
+    }
+
+    /// Asks for a full wallet, worth `2^height`.
+    pub fn issue_request(&mut self) -> IssueRequest {
+        self.issue_request_with_rng(&mut OsRng)
+    }
+
+    /// Like `issue_request`, but draws the master key and commitment opening
+    /// from `rng`.
+    pub fn issue_request_with_rng<R: Rng + CryptoRng>(&mut self, rng: &mut R) -> IssueRequest {
+        self.new_wallet(1 << self.pp.height, rng)
+    }
+
+    /// Asks for a wallet worth only `value`, of at most `2^height`. Leaves
+    /// past the value can't be spent.
+    pub fn issue_request_for(&mut self, value: u64) -> Result<IssueRequest, DapError> {
+        self.issue_request_for_with_rng(value, &mut OsRng)
+    }
+
+    /// Like `issue_request_for`, but draws the master key and commitment
+    /// opening from `rng`.
+    pub fn issue_request_for_with_rng<R: Rng + CryptoRng>(&mut self,
+                                                          value: u64,
+                                                          rng: &mut R)
+                                                          -> Result<IssueRequest, DapError> {
+        // The exchange only signs values the tree can hold
+        if value == 0 || value > 1u64 << self.pp.height {
+            return Err(DapError::InvalidValue(value));
+        }
+        Ok(self.new_wallet(value, rng))
+    }
+
+    // Adds a pending wallet worth `value` and asks for it to be issued
+    fn new_wallet<R: Rng + CryptoRng>(&mut self, value: u64, rng: &mut R) -> IssueRequest {
+        // Generate master key
+        let key = rng.gen::<[u8; 32]>();
+
+        // Compute GGM-tree leaves
+        let ggm = GGM::new();
+        let leaves_bytes = ggm.expand(&key, self.pp.height.try_into().unwrap());
+        let mut leaves = Vec::new();
+        for bytes in leaves_bytes {
+            leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
+        }
+
+        // Construct Merkle tree and hash to root
+        let tree = MerkleTree::new(&self.pp.hasher, &leaves).unwrap();
+        let root = tree.root();
+        let tag = self.pp.hasher.hash(&[root]).unwrap();
+
+        // Compute commitment
+        let open = Fr::rand(rng);
+        let com = self.pp.hasher.hash(&[root, open]).unwrap();
+
+        // Update wallet
+        let id = self.next_id;
+        self.next_id += 1;
+        // Leaves past the value are never spendable
+        let mut spent = BitVec::from_elem(leaves.len(), false);
+        for i in (value as usize)..leaves.len() {
+            spent.set(i, true);
+        }
+        let entry = Entry {
+            id,
+            key,
+            leaves,
+            tree,
+            root,
+            tag,
+            epoch: 0,
+            com,
+            open,
+            value,
+            sig: None,
+            proofs: BTreeMap::new(),
+            spent,
+        };
+        self.wallet.push(entry);
+
+        let mut com_bytes = vec![];
+        com.serialize(&mut com_bytes).unwrap();
+
+        IssueRequest { id, value, com: com_bytes }
+    }
+
+    pub fn issue_process(&mut self, rsp: IssueResponse) -> Result<(), DapError> {
+        let i = self.wallet
+            .iter()
+            .position(|entry| entry.id == rsp.id)
+            .ok_or(DapError::UnknownRequest(rsp.id))?;
+        let pp = self.pp_at(rsp.epoch)?;
+        // XXX: Rename type
+        let prover_response = FpEd::deserialize(&*rsp.sig.prover_response)?;
+        let sig = Signature::<JubJub> {
+            prover_response,
+            verifier_challenge: rsp.sig.verifier_challenge,
+        };
+        let msg = circuit::signed_message(&pp.hasher, self.wallet[i].com, self.wallet[i].value)?;
+        if !SchnorrJ::verify(&pp.sig_params,
+                             &pp.pk,
+                             &msg,
+                             &sig)? {
+            return Err(DapError::InvalidSignature);
+        }
+        let entry = &mut self.wallet[i];
+        entry.epoch = rsp.epoch;
+        entry.sig = Some(sig);
+        Ok(())
+    }
+
+    /// Proves every unspent node of the issued wallets ahead of time, for
+    /// coins spent in `ctx`, so any payment can be made from precomputed
+    /// proofs. A fresh wallet of height `h` takes `2^(h + 1) - 1` proofs.
+    pub fn precompute_proofs(&mut self, ctx: &Context) -> Result<(), DapError> {
+        self.precompute_proofs_with_rng(ctx, &mut OsRng)
+    }
+
+    /// Like `precompute_proofs`, but draws the proof randomness from `rng`.
+    pub fn precompute_proofs_with_rng<R: Rng + CryptoRng>(&mut self,
+                                                          ctx: &Context,
+                                                          rng: &mut R)
+                                                          -> Result<(), DapError> {
+        let height = self.pp.height;
+        let mut nodes = vec![];
+        for i in self.issued()? {
+            for depth in 0..=height {
+                for pos in 0..(1u64 << depth) {
+                    if unspent(&self.wallet[i].spent, height, depth, pos) {
+                        nodes.push((i, depth, pos));
+                    }
                 }
+            }
+        }
+        self.prove_nodes(&nodes, ctx, rng)
+    }
 
-                // Construct Merkle tree and hash to root
-                let smt = $smt::new_sequential(&leaves,
-                                              &self.pp.hasher,
-                                              &DEFAULT_LEAF).unwrap();
-                let root = smt.root();
-
-                // Compute commitment
-                let open = Fr::rand(rng);
-                let com = self.pp.hasher.hash(&[root, open]).unwrap();
-
-                // Update wallet
-                let entry = $wallet {
-                    key,
-                    leaves,
-                    smt,
-                    root,
-                    com,
-                    open,
-                    sig: None,
-                    coins: vec![]
-                };
-                self.wallet.push(entry);
-
-                let mut com_bytes = vec![];
-                com.serialize(&mut com_bytes).unwrap();
-                
-                IssueRequest { com: com_bytes }
+    /// Proves only the nodes the next payment of `n` in `ctx` would spend.
+    pub fn precompute_proofs_for(&mut self, n: u16, ctx: &Context) -> Result<(), DapError> {
+        self.precompute_proofs_for_with_rng(n, ctx, &mut OsRng)
+    }
+
+    /// Like `precompute_proofs_for`, but draws the proof randomness from
+    /// `rng`.
+    pub fn precompute_proofs_for_with_rng<R: Rng + CryptoRng>(&mut self,
+                                                              n: u16,
+                                                              ctx: &Context,
+                                                              rng: &mut R)
+                                                              -> Result<(), DapError> {
+        let issued = self.issued()?;
+        let spent: Vec<BitVec> = issued
+            .iter()
+            .map(|&i| self.wallet[i].spent.clone())
+            .collect();
+        let nodes: Vec<(usize, usize, u64)> = allocate(&spent, self.pp.height, n)
+            .ok_or(DapError::InsufficientFunds(n))?
+            .into_iter()
+            .map(|(w, depth, pos)| (issued[w], depth, pos))
+            .collect();
+        self.prove_nodes(&nodes, ctx, rng)
+    }
+
+    // Returns the indices of the issued wallets
+    fn issued(&self) -> Result<Vec<usize>, DapError> {
+        if self.wallet.is_empty() {
+            return Err(DapError::NoWallet);
+        }
+        let issued: Vec<usize> = (0..self.wallet.len())
+            .filter(|&i| self.wallet[i].sig.is_some())
+            .collect();
+        if issued.is_empty() {
+            return Err(DapError::NotIssued);
+        }
+        Ok(issued)
+    }
+
+    // Proves each `(wallet, depth, pos)` node for `ctx`, unless it already
+    // has a proof for it
+    fn prove_nodes<R: Rng + CryptoRng>(&mut self,
+                                       nodes: &[(usize, usize, u64)],
+                                       ctx: &Context,
+                                       rng: &mut R)
+                                       -> Result<(), DapError> {
+        for &(i, depth, pos) in nodes {
+            let index = node_index(depth, pos);
+            let entry = &self.wallet[i];
+            if entry.proofs.get(&index).map_or(false, |(c, _)| c == ctx) {
+                continue;
             }
+            let sig = entry.sig.as_ref().ok_or(DapError::NotIssued)?;
+            let witness = dispatch::Witness {
+                sig,
+                root: entry.root,
+                com: entry.com,
+                open: entry.open,
+                value: entry.value,
+            };
+
+            // Prove the subtree's root, an internal node of the wallet's
+            // tree unless the subtree is a single leaf, under the wallet's
+            // epoch
+            let pp = if entry.epoch == self.pp.epoch {
+                &self.pp
+            } else {
+                self.past.get(&entry.epoch).ok_or(DapError::UnknownEpoch(entry.epoch))?
+            };
+            let node = (entry.tree.node(depth, pos), depth, pos, entry.tree.path(depth, pos));
+            let proof = dispatch::prove(pp, &witness, ctx.to_field(&pp.hasher), node, rng)?;
+            let mut proof_bytes = vec![];
+            proof.serialize(&mut proof_bytes)?;
+            self.wallet[i].proofs.insert(index, (*ctx, proof_bytes));
+        }
+        Ok(())
+    }
 
-            pub fn issue_process(&mut self, rsp: IssueResponse) {
-                // XXX: Pick out right entry
-                let mut entry = &mut self.wallet[0];
-                // XXX: Rename type
-                let prover_response = FpEd::deserialize(&*rsp.sig.prover_response).unwrap();
-                let sig = Signature::<JubJub> {
-                    prover_response,
-                    verifier_challenge: rsp.sig.verifier_challenge,
-                };
-                assert!(SchnorrJ::verify(&self.pp.sig_params,
-                                         &self.pp.pk,
-                                         &entry.com,
-                                         &sig).unwrap());
-                entry.sig = Some(sig);
+    /// Spends `n` in `ctx`, from proofs precomputed for it.
+    pub fn redeem_request(&mut self, n: u16, ctx: &Context) -> Result<RedeemRequest, DapError> {
+        let issued = self.issued()?;
+        let height = self.pp.height;
+        let spent: Vec<BitVec> = issued
+            .iter()
+            .map(|&i| self.wallet[i].spent.clone())
+            .collect();
+        let nodes = allocate(&spent, height, n)
+            .ok_or(DapError::InsufficientFunds(n))?;
+
+        let mut coins = vec![];
+        for &(w, depth, pos) in nodes.iter() {
+            let entry = &self.wallet[issued[w]];
+            let index = node_index(depth, pos);
+            let proof_bytes = match entry.proofs.get(&index) {
+                Some((c, proof_bytes)) if c == ctx => proof_bytes.clone(),
+                _ => return Err(DapError::MissingProof(index)),
+            };
+
+            coins.push(Coin {
+                epoch: entry.epoch,
+                context: *ctx,
+                denom: (height - depth) as u8,
+                spend: self.spend(issued[w], depth, pos)?,
+                proof_bytes,
+            });
+        }
+
+        self.mark_spent(&issued, &nodes);
+        Ok(RedeemRequest { publisher: ctx.publisher, coins, bundles: vec![] })
+    }
+
+    /// Spends `n` in `ctx` in bundles, proving the nodes drawn from each
+    /// wallet together, as many at a time as the exchange's bundle circuit
+    /// takes. The proofs depend on which nodes are spent, so they are
+    /// computed here rather than ahead of time.
+    pub fn redeem_bundled(&mut self, n: u16, ctx: &Context) -> Result<RedeemRequest, DapError> {
+        self.redeem_bundled_with_rng(n, ctx, &mut OsRng)
+    }
+
+    /// Like `redeem_bundled`, but draws the proof randomness from `rng`.
+    pub fn redeem_bundled_with_rng<R: Rng + CryptoRng>(&mut self,
+                                                       n: u16,
+                                                       ctx: &Context,
+                                                       rng: &mut R)
+                                                       -> Result<RedeemRequest, DapError> {
+        let issued = self.issued()?;
+        let height = self.pp.height;
+        let spent: Vec<BitVec> = issued
+            .iter()
+            .map(|&i| self.wallet[i].spent.clone())
+            .collect();
+        let nodes = allocate(&spent, height, n)
+            .ok_or(DapError::InsufficientFunds(n))?;
+
+        let mut bundles = vec![];
+        for (w, &i) in issued.iter().enumerate() {
+            let wallet_nodes: Vec<(usize, u64)> = nodes
+                .iter()
+                .filter(|&&(v, _, _)| v == w)
+                .map(|&(_, depth, pos)| (depth, pos))
+                .collect();
+            if wallet_nodes.is_empty() {
+                continue;
+            }
+            let entry = &self.wallet[i];
+            let sig = entry.sig.as_ref().ok_or(DapError::NotIssued)?;
+            let witness = dispatch::Witness {
+                sig,
+                root: entry.root,
+                com: entry.com,
+                open: entry.open,
+                value: entry.value,
+            };
+            let pp = self.pp_at(entry.epoch)?;
+            if pp.bundle == 0 {
+                return Err(DapError::NoBundles);
             }
-            
-            pub fn precompute_proofs(&mut self) {
-                let rng = &mut test_rng();
-                let entry = &mut self.wallet[0];
-                
-                // Generate proof for leaf 0
-                let path = entry.smt.generate_membership_proof(0);
-                let circuit = $circ::new(self.pp.sig_params.clone(),
-                                          self.pp.pk,
-                                          entry.sig.clone().unwrap(),
-                                          entry.root,
-                                          entry.com,
-                                          entry.open,
-                                          entry.leaves[0],
-                                          path,
-                                          self.pp.hasher.clone());
-
-                let proof = GrothSetup::prove(
-                    &self.pp.groth_pks[$height],
-                    circuit,
-                    rng).unwrap();
+
+            for chunk in wallet_nodes.chunks(pp.bundle) {
+                let proof_nodes: Vec<(Fp, usize, u64, Vec<(Fp, Fp)>)> = chunk
+                    .iter()
+                    .map(|&(depth, pos)| {
+                        (entry.tree.node(depth, pos), depth, pos, entry.tree.path(depth, pos))
+                    })
+                    .collect();
+                let proof = dispatch::prove_bundle(pp,
+                                                   &witness,
+                                                   ctx.to_field(&pp.hasher),
+                                                   &proof_nodes,
+                                                   rng)?;
                 let mut proof_bytes = vec![];
-                proof.serialize(&mut proof_bytes).unwrap();
+                proof.serialize(&mut proof_bytes)?;
+
+                let mut coins = vec![];
+                for &(depth, pos) in chunk {
+                    coins.push(((height - depth) as u8, self.spend(i, depth, pos)?));
+                }
+                bundles.push(Bundle {
+                    epoch: entry.epoch,
+                    context: *ctx,
+                    coins,
+                    proof_bytes,
+                });
+            }
+        }
+
+        self.mark_spent(&issued, &nodes);
+        Ok(RedeemRequest { publisher: ctx.publisher, coins: vec![], bundles })
+    }
+
+    // Reveals the node at `depth` with position `pos` in wallet `i` as the
+    // exchange's mode asks
+    fn spend(&self, i: usize, depth: usize, pos: u64) -> Result<Spend, DapError> {
+        let entry = &self.wallet[i];
+        match self.pp.mode {
+            Mode::Revealed => {
+                // Constrained key for the subtree rooted at the node
+                let label = u16_to_bv(pos as u16, depth);
+                let key = GGM::new().eval(&entry.key, &label);
+
+                let mut tag_bytes = vec![];
+                entry.tag.serialize(&mut tag_bytes)?;
                 let mut instance_bytes = vec![];
-                entry.leaves[0].serialize(&mut instance_bytes).unwrap();
-                
-                let coin = Coin {
-                    denom: 0,
-                    key: entry.key,
-                    instance_bytes,
-                    proof_bytes
-                };
-                entry.coins.push(coin);                
+                entry.tree.node(depth, pos).serialize(&mut instance_bytes)?;
+                Ok(Spend::Revealed { key, tag_bytes, pos, instance_bytes })
+            }
+            Mode::Nullified => {
+                // Nothing that leads back to the wallet's key or tree
+                let mut nullifiers = vec![];
+                for nf in circuit::nullifiers(&self.pp.hasher, entry.root, depth, pos)? {
+                    let mut nf_bytes = vec![];
+                    nf.serialize(&mut nf_bytes)?;
+                    nullifiers.push(nf_bytes);
+                }
+                Ok(Spend::Nullified { nullifiers })
             }
+        }
+    }
 
-            pub fn redeem_request(&mut self, _n: u16) -> RedeemRequest {
-                // XXX: Uses preloaded coins
-                let mut coins = vec![];        
-                let coin = self.wallet[0].coins[0].clone();
-                coins.push(coin);
-                RedeemRequest { coins }
+    // Marks the leaves under each `(wallet, depth, pos)` node as spent, where
+    // wallets index `issued`
+    fn mark_spent(&mut self, issued: &[usize], nodes: &[(usize, usize, u64)]) {
+        let height = self.pp.height;
+        for &(w, depth, pos) in nodes.iter() {
+            let entry = &mut self.wallet[issued[w]];
+            let width = 1usize << (height - depth);
+            let start = (pos as usize) * width;
+            for i in start..(start + width) {
+                entry.spent.set(i, true);
             }
         }
+        // Proofs of nodes overlapping the spent ones can no longer be used
+        for &(w, _, _) in nodes.iter() {
+            let entry = &mut self.wallet[issued[w]];
+            let spent = &entry.spent;
+            entry.proofs.retain(|&index, _| {
+                let (depth, pos) = node_at(index);
+                unspent(spent, height, depth, pos)
+            });
+        }
     }
 }
-
-mk_client![Entry, SMT, Client, HEIGHT, SpendC];
-mk_client![Entry0 , SMT0 , Client0 , HEIGHT0 , C0 ];
-mk_client![Entry1 , SMT1 , Client1 , HEIGHT1 , C1 ];
-mk_client![Entry2 , SMT2 , Client2 , HEIGHT2 , C2 ];
-mk_client![Entry3 , SMT3 , Client3 , HEIGHT3 , C3 ];
-mk_client![Entry4 , SMT4 , Client4 , HEIGHT4 , C4 ];
-mk_client![Entry5 , SMT5 , Client5 , HEIGHT5 , C5 ];
-mk_client![Entry6 , SMT6 , Client6 , HEIGHT6 , C6 ];
-mk_client![Entry7 , SMT7 , Client7 , HEIGHT7 , C7 ];
-mk_client![Entry8 , SMT8 , Client8 , HEIGHT8 , C8 ];
-mk_client![Entry9 , SMT9 , Client9 , HEIGHT9 , C9 ];
-mk_client![Entry10, SMT10, Client10, HEIGHT10, C10];
-mk_client![Entry11, SMT11, Client11, HEIGHT11, C11];
-mk_client![Entry12, SMT12, Client12, HEIGHT12, C12];
@@ -0,0 +1,233 @@
+//! Runtime dispatch from a wallet height to the circuit instantiated for it.
+//!
+//! The spend circuit is monomorphised over the wallet height, and proves
+//! nodes at any depth of such a wallet, one per slot. The functions here pick
+//! the right instantiation, up to `MAX_HEIGHT`, from a runtime height.
+use ark_bls12_381::Fr;
+use ark_crypto_primitives::SNARK;
+use ark_ed_on_bls12_381::EdwardsProjective as JubJub;
+use ark_ff::{Fp256, PrimeField, UniformRand, Zero};
+use ark_std::rand::{CryptoRng, Rng};
+use arkworks_native_gadgets::poseidon::{FieldHasher, Poseidon};
+
+use crate::dap::{
+    circuit::{self, Slot},
+    error::DapError,
+    tree::MerkleTree,
+    types::*,
+};
+use crate::ggm::GGM;
+use crate::schnorr::{Signature, SignatureScheme};
+
+macro_rules! dispatch {
+    ($lvl:expr, $f:ident($($arg:expr),*)) => {
+        match $lvl {
+            0  => $f::<0 , _>($($arg),*),
+            1  => $f::<1 , _>($($arg),*),
+            2  => $f::<2 , _>($($arg),*),
+            3  => $f::<3 , _>($($arg),*),
+            4  => $f::<4 , _>($($arg),*),
+            5  => $f::<5 , _>($($arg),*),
+            6  => $f::<6 , _>($($arg),*),
+            7  => $f::<7 , _>($($arg),*),
+            8  => $f::<8 , _>($($arg),*),
+            9  => $f::<9 , _>($($arg),*),
+            10 => $f::<10, _>($($arg),*),
+            11 => $f::<11, _>($($arg),*),
+            12 => $f::<12, _>($($arg),*),
+            height => panic!("No circuit for height {}", height),
+        }
+    };
+}
+
+/// What the owner of a wallet proves it holds in every spend: the issuer's
+/// signature `sig` on the commitment `com` to `root` with opening `open`, and
+/// the wallet's `value` signed with it.
+pub struct Witness<'a> {
+    pub sig: &'a Signature<JubJub>,
+    pub root: Fp,
+    pub com: Fp,
+    pub open: Fp,
+    pub value: u64,
+}
+
+/// Runs the setup of the circuit for wallets of height `height` in `mode`,
+/// with `slots` nodes per proof. The keys work for nodes at every depth,
+/// under any issuer key under `sig_params`.
+pub fn setup<R: Rng + CryptoRng>(height: usize,
+                                 mode: Mode,
+                                 slots: usize,
+                                 sig_params: &SigParams,
+                                 hasher: &Poseidon<Fr>,
+                                 rng: &mut R)
+                                 -> (GrothProvingKey, GrothVerifyingKey) {
+    dispatch!(height, setup_at(mode, slots, sig_params, hasher, rng))
+}
+
+/// Lays out the public inputs of a proof that the node `node`, at `depth`
+/// and position `pos` in its level of the wallet tagged `tag`, was issued
+/// under `pk` and is spent in the context hashed to `ctx`.
+pub fn inputs(pk: &SigPublicKey,
+              node: Fp,
+              tag: Fp,
+              depth: usize,
+              pos: u64,
+              ctx: Fp)
+              -> Vec<Fp> {
+    bundle_inputs(pk, tag, &[(node, depth, pos)], ctx, 1)
+}
+
+/// Lays out the public inputs of a proof with `slots` slots that each
+/// `(node, depth, pos)` of `nodes` is in the wallet tagged `tag`. Slots left
+/// over repeat the last node.
+pub fn bundle_inputs(pk: &SigPublicKey,
+                     tag: Fp,
+                     nodes: &[(Fp, usize, u64)],
+                     ctx: Fp,
+                     slots: usize)
+                     -> Vec<Fp> {
+    let mut inputs = vec![pk.x, pk.y, ctx, tag];
+    for i in 0..slots {
+        let (node, depth, pos) = nodes[i.min(nodes.len() - 1)];
+        inputs.extend_from_slice(&[node, Fp::from(pos), Fp::from(depth as u64)]);
+    }
+    inputs
+}
+
+/// Lays out the public inputs of a proof that the node at `depth` whose
+/// ancestors and itself have the nullifiers `nullifiers`, in a wallet of
+/// height `height`, was issued under `pk` and is spent in the context hashed
+/// to `ctx`.
+pub fn nullified_inputs(pk: &SigPublicKey,
+                        height: usize,
+                        depth: usize,
+                        nullifiers: &[Fp],
+                        ctx: Fp)
+                        -> Vec<Fp> {
+    nullified_bundle_inputs(pk, height, &[(depth, nullifiers.to_vec())], ctx, 1)
+}
+
+/// Like `bundle_inputs`, for nodes given by their depth and nullifiers.
+pub fn nullified_bundle_inputs(pk: &SigPublicKey,
+                               height: usize,
+                               nodes: &[(usize, Vec<Fp>)],
+                               ctx: Fp,
+                               slots: usize)
+                               -> Vec<Fp> {
+    let mut inputs = vec![pk.x, pk.y, ctx];
+    for i in 0..slots {
+        let (depth, nullifiers) = &nodes[i.min(nodes.len() - 1)];
+        inputs.push(Fp::from(*depth as u64));
+        inputs.extend_from_slice(nullifiers);
+        // One nullifier per level, zero below the node
+        inputs.resize(inputs.len() + height + 1 - nullifiers.len(), Fp::zero());
+    }
+    inputs
+}
+
+/// Proves that `node`, given as `(node, depth, pos, path)` with `pos` its
+/// position in its level, lies on `path` to the root of `witness`, below the
+/// wallet's value, and that the issuer signed the wallet's commitment with
+/// that value, for the context `ctx`.
+pub fn prove<R: Rng + CryptoRng>(pp: &PP,
+                                 witness: &Witness,
+                                 ctx: Fp,
+                                 node: (Fp, usize, u64, Vec<(Fp, Fp)>),
+                                 rng: &mut R)
+                                 -> Result<GrothProof, DapError> {
+    dispatch!(pp.height, prove_at(pp, &pp.groth_pk, 1, witness, ctx, &[node], rng))
+}
+
+/// Proves with the bundle circuit of `pp` that each `(node, depth, pos,
+/// path)` of `nodes` lies on its path to the root of `witness`, as in
+/// `prove`.
+pub fn prove_bundle<R: Rng + CryptoRng>(pp: &PP,
+                                        witness: &Witness,
+                                        ctx: Fp,
+                                        nodes: &[(Fp, usize, u64, Vec<(Fp, Fp)>)],
+                                        rng: &mut R)
+                                        -> Result<GrothProof, DapError> {
+    let bundle_pk = pp.bundle_pk.as_ref().ok_or(DapError::NoBundles)?;
+    if nodes.is_empty() || nodes.len() > pp.bundle {
+        return Err(DapError::BundleSize(nodes.len()));
+    }
+    dispatch!(pp.height, prove_at(pp, bundle_pk, pp.bundle, witness, ctx, nodes, rng))
+}
+
+fn setup_at<const N: usize, R: Rng + CryptoRng>(mode: Mode,
+                                                slots: usize,
+                                                sig_params: &SigParams,
+                                                hasher: &Poseidon<Fr>,
+                                                rng: &mut R)
+                                                -> (GrothProvingKey, GrothVerifyingKey) {
+    // The issuer key is a public input, so any key will do for the setup
+    let (pk, sk) = SchnorrJ::keygen(sig_params, rng).unwrap();
+
+    // Expand constrained PRF to generate Merkle tree leaves
+    let key = rng.gen::<[u8; 32]>();
+    let ggm = GGM::new();
+    let leaves_bytes = ggm.expand(&key, N.try_into().unwrap());
+    let mut leaves: Vec<Fp> = Vec::new();
+    for bytes in leaves_bytes {
+        leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
+    }
+
+    // Construct Merkle tree and hash to root
+    let tree = MerkleTree::new(hasher, &leaves).unwrap();
+    let root = tree.root();
+    // Generate path for membership proof of leaf with label 0
+    let path = tree.path(N, 0);
+
+    // Generate commitment to the root
+    let open = Fr::rand(rng);
+    let com = hasher.hash(&[root, open]).unwrap();
+
+    // Generate a signature on com and the value of a full wallet under pk
+    let value = 1u64 << N;
+    let msg = circuit::signed_message(hasher, com, value).unwrap();
+    let sig = SchnorrJ::sign(sig_params, &sk, &msg, rng).unwrap();
+    assert!(SchnorrJ::verify(sig_params, &pk, &msg, &sig).unwrap());
+
+    // Run trusted setup for circuit, with leaf 0 in every slot
+    let setup_circuit = SpendC::<N>::with_slots(sig_params.clone(),
+                                                pk,
+                                                sig,
+                                                root,
+                                                com,
+                                                open,
+                                                value,
+                                                Fp::zero(),
+                                                vec![Slot::new(leaves[0], N, 0, &path); slots],
+                                                hasher.clone(),
+                                                mode == Mode::Nullified);
+    GrothSetup::circuit_specific_setup(setup_circuit, rng).unwrap()
+}
+
+fn prove_at<const N: usize, R: Rng + CryptoRng>(pp: &PP,
+                                                groth_pk: &GrothProvingKey,
+                                                slots: usize,
+                                                witness: &Witness,
+                                                ctx: Fp,
+                                                nodes: &[(Fp, usize, u64, Vec<(Fp, Fp)>)],
+                                                rng: &mut R)
+                                                -> Result<GrothProof, DapError> {
+    // Slots left over repeat the last node
+    let slots = (0..slots)
+        .map(|i| {
+            let (node, depth, pos, path) = &nodes[i.min(nodes.len() - 1)];
+            Slot::new(*node, *depth, *pos, path)
+        })
+        .collect();
+    let circuit = SpendC::<N>::with_slots(pp.sig_params.clone(),
+                                          pp.pk,
+                                          witness.sig.clone(),
+                                          witness.root,
+                                          witness.com,
+                                          witness.open,
+                                          witness.value,
+                                          ctx,
+                                          slots,
+                                          pp.hasher.clone(),
+                                          pp.mode == Mode::Nullified);
+    Ok(GrothSetup::prove(groth_pk, circuit, rng)?)
+}
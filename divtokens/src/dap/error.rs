@@ -0,0 +1,95 @@
+use ark_relations::r1cs::SynthesisError;
+use ark_serialize::SerializationError;
+use std::io;
+
+use crate::billing::BillingError;
+
+/// Error enum for the DAP protocol.
+#[derive(Debug)]
+pub enum DapError {
+    /// Thrown when a message field cannot be (de)serialized.
+    Serialization(SerializationError),
+    /// Thrown when the issuer's signature on a commitment does not verify.
+    InvalidSignature,
+    /// Thrown when the client has no wallet to act on.
+    NoWallet,
+    /// Thrown when an issue response matches no pending wallet.
+    UnknownRequest(u64),
+    /// Thrown when a message names an epoch whose keys are not known.
+    UnknownEpoch(u64),
+    /// Thrown when a wallet is used before its issue response was processed.
+    NotIssued,
+    /// Thrown when the wallet cannot cover the requested amount.
+    InsufficientFunds(u16),
+    /// Thrown when a wallet is asked for a value its tree cannot hold.
+    InvalidValue(u64),
+    /// Thrown when a node has no precomputed proof.
+    MissingProof(u64),
+    /// Thrown when a redeem request exceeds the server's limits.
+    RequestTooLarge,
+    /// Thrown when bundling coins the exchange has no bundle circuit for.
+    NoBundles,
+    /// Thrown when a bundle has no nodes or more than its circuit's slots.
+    BundleSize(usize),
+    /// Thrown when the advertiser's account cannot pay for issuance.
+    Billing(BillingError),
+    /// Thrown when a signature, hash or proof system primitive fails.
+    Crypto(String),
+    /// Thrown when the spent-token store or a key file cannot be read or
+    /// written.
+    Io(io::Error),
+}
+
+impl core::fmt::Display for DapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DapError::Serialization(e) => write!(f, "Malformed message: {}", e),
+            DapError::InvalidSignature => write!(f, "Invalid issuer signature"),
+            DapError::NoWallet => write!(f, "No wallet"),
+            DapError::UnknownRequest(id) => write!(f, "No pending issuance with request ID {}", id),
+            DapError::UnknownEpoch(e) => write!(f, "No keys for epoch {}", e),
+            DapError::NotIssued => write!(f, "Wallet has not been issued"),
+            DapError::InsufficientFunds(n) => write!(f, "Wallet cannot cover {}", n),
+            DapError::InvalidValue(v) => write!(f, "No wallet is worth {}", v),
+            DapError::MissingProof(i) => write!(f, "No proof precomputed for node {} in this context", i),
+            DapError::RequestTooLarge => write!(f, "Redeem request exceeds limits"),
+            DapError::NoBundles => write!(f, "Bundles are not accepted"),
+            DapError::BundleSize(n) => write!(f, "No bundle circuit for {} nodes", n),
+            DapError::Billing(e) => write!(f, "Billing failure: {}", e),
+            DapError::Crypto(e) => write!(f, "Cryptographic failure: {}", e),
+            DapError::Io(e) => write!(f, "I/O failure: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DapError {}
+
+impl From<SerializationError> for DapError {
+    fn from(e: SerializationError) -> Self {
+        DapError::Serialization(e)
+    }
+}
+
+impl From<SynthesisError> for DapError {
+    fn from(e: SynthesisError) -> Self {
+        DapError::Crypto(e.to_string())
+    }
+}
+
+impl From<ark_crypto_primitives::Error> for DapError {
+    fn from(e: ark_crypto_primitives::Error) -> Self {
+        DapError::Crypto(e.to_string())
+    }
+}
+
+impl From<BillingError> for DapError {
+    fn from(e: BillingError) -> Self {
+        DapError::Billing(e)
+    }
+}
+
+impl From<io::Error> for DapError {
+    fn from(e: io::Error) -> Self {
+        DapError::Io(e)
+    }
+}
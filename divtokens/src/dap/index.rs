@@ -0,0 +1,107 @@
+//! Index of spent subtree nodes over a `SpentStore`.
+//!
+//! A spent coin is recorded as its node, identified by the issuer key epoch
+//! and an id, rather than as the leaves under it. Revealed nodes are
+//! identified by the wallet tag and the node's depth and position, and
+//! nullified ones by their nullifier. Each node gets one of two marks:
+//!
+//! - `SPENT` on the node itself, and
+//! - `ABOVE` on each of its strict ancestors, meaning something below them
+//!   has been spent.
+//!
+//! A node overlaps earlier spends iff it or one of its ancestors is `SPENT`,
+//! or it is itself `ABOVE` a spent node. Both checks and updates walk one
+//! root-to-node path, so their cost depends on the tree height and not on the
+//! coin's value.
+//!
+//! Keys start with the epoch, so every spend of an epoch can be dropped by
+//! its `epoch_prefix`.
+use std::io;
+
+use crate::spent::SpentStore;
+
+const SPENT: u8 = 0;
+const ABOVE: u8 = 1;
+
+/// Returns the prefix of every key recorded for `epoch`.
+pub fn epoch_prefix(epoch: u64) -> [u8; 8] {
+    epoch.to_be_bytes()
+}
+
+fn key(epoch: u64, mark: u8, id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(8 + 1 + id.len());
+    key.extend_from_slice(&epoch_prefix(epoch));
+    key.push(mark);
+    key.extend_from_slice(id);
+    key
+}
+
+/// Returns the ids of the root-to-node path of the revealed node at `depth`
+/// with position `pos` in the wallet tagged `tag`, root first.
+pub fn path(tag: &[u8], depth: usize, pos: u64) -> Vec<Vec<u8>> {
+    (0..=depth)
+        .map(|d| {
+            let mut id = Vec::with_capacity(tag.len() + 1 + 8);
+            id.extend_from_slice(tag);
+            id.push(d as u8);
+            id.extend_from_slice(&(pos >> (depth - d)).to_le_bytes());
+            id
+        })
+        .collect()
+}
+
+/// Returns whether the node whose root-to-node path has the ids `path`,
+/// issued in `epoch`, overlaps a node already marked in `store`.
+pub fn overlaps_path<S: SpentStore, I: AsRef<[u8]>>(store: &S, epoch: u64, path: &[I]) -> bool {
+    match path.last() {
+        Some(node) => {
+            path.iter().any(|id| store.contains(&key(epoch, SPENT, id.as_ref())))
+                || store.contains(&key(epoch, ABOVE, node.as_ref()))
+        }
+        None => false,
+    }
+}
+
+/// Returns the keys that mark the node whose root-to-node path has the ids
+/// `path`, issued in `epoch`, as spent.
+pub fn marks<I: AsRef<[u8]>>(epoch: u64, path: &[I]) -> Vec<Vec<u8>> {
+    match path.split_last() {
+        Some((node, ancestors)) => ancestors
+            .iter()
+            .map(|id| key(epoch, ABOVE, id.as_ref()))
+            .chain(std::iter::once(key(epoch, SPENT, node.as_ref())))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Marks the node whose root-to-node path has the ids `path`, issued in
+/// `epoch`, as spent in `store`.
+pub fn mark_path<S: SpentStore, I: AsRef<[u8]>>(store: &mut S,
+                                                epoch: u64,
+                                                path: &[I])
+                                                -> io::Result<()> {
+    store.insert_all(&marks(epoch, path))
+}
+
+/// Returns whether the node at `depth` with position `pos` in the wallet
+/// tagged `tag`, issued in `epoch`, overlaps a node already marked in `store`.
+pub fn overlaps<S: SpentStore>(store: &S,
+                               epoch: u64,
+                               tag: &[u8],
+                               depth: usize,
+                               pos: u64)
+                               -> bool {
+    overlaps_path(store, epoch, &path(tag, depth, pos))
+}
+
+/// Marks the node at `depth` with position `pos` in the wallet tagged `tag`,
+/// issued in `epoch`, as spent in `store`.
+pub fn mark<S: SpentStore>(store: &mut S,
+                           epoch: u64,
+                           tag: &[u8],
+                           depth: usize,
+                           pos: u64)
+                           -> io::Result<()> {
+    mark_path(store, epoch, &path(tag, depth, pos))
+}
@@ -1,23 +1,30 @@
 use serde_derive::{Deserialize, Serialize};
 
 use crate::dap::types::*;
+pub use crate::redemption::RedeemResponse;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IssueRequest {
+    // Chosen by the client to match the response to its wallet
+    pub id: u64,
+    // Leaves the wallet is paid for, signed with the commitment
+    pub value: u64,
     pub com: Vec<u8>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IssueResponse {
+    pub id: u64,
+    // Epoch of the key that signed
+    pub epoch: u64,
     pub sig: SchnorrSig,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RedeemRequest {
-    pub coins: Vec<Coin>
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RedeemResponse {
-    pub valid: bool,
+    // Publisher redeeming the coins, credited with their value
+    pub publisher: u64,
+    pub coins: Vec<Coin>,
+    // Coins spent under one proof per bundle, settled after the coins above
+    pub bundles: Vec<Bundle>,
 }
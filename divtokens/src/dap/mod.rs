@@ -1,7 +1,12 @@
+pub mod batch;
 pub mod circuit;
 pub mod client;
+pub mod dispatch;
+pub mod error;
+pub mod index;
 pub mod messages;
 pub mod server;
+pub mod tree;
 pub mod types;
 
 #[cfg(test)]
@@ -10,6 +15,7 @@ mod test {
     use ark_bls12_381::Fr;    
     use ark_crypto_primitives::SNARK;    
     use ark_ff::{Fp256, PrimeField, UniformRand};
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem};
     use ark_serialize::*;
     use ark_std::test_rng;
     use arkworks_native_gadgets::poseidon::{
@@ -20,45 +26,800 @@ mod test {
     use bloomfilter::Bloom;
     use rand::Rng;
     use serial_test::serial;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
     use std::{
-        fs::{create_dir_all, File},
+        env,
+        fs::{self, create_dir_all, File},
+        process,
+        sync::Arc,
+        thread,
         time::{Duration, Instant},
     };
 
+    use crate::billing::{BillingError, Earnings, Item, Ledger, Period, Scheme};
+    use crate::dap::batch::{self, Claim};
+    use crate::dap::circuit;
     use crate::dap::client::*;
+    use crate::dap::dispatch;
+    use crate::dap::error::DapError;
+    use crate::dap::index;
     use crate::dap::messages::*;    
     use crate::dap::server::*;
+    use crate::dap::tree::*;
     use crate::dap::types::*;
     use crate::ggm::{GGM, u16_to_bv};    
+    use crate::redemption::{CoinResult, Rejection};
     use crate::schnorr::SignatureScheme;
+    use crate::spent::{ExactStore, Sharded, SpentStore};
+
+    const CTX: Context = Context { publisher: 1, auction: 1 };
+    const ADVERTISER: u64 = 1;
+
+    // Tag, position and node bytes of a revealed coin
+    fn revealed(coin: &Coin) -> (&[u8], u64, &[u8]) {
+        match &coin.spend {
+            Spend::Revealed { tag_bytes, pos, instance_bytes, .. } => {
+                (tag_bytes, *pos, instance_bytes)
+            }
+            Spend::Nullified { .. } => panic!("Expected a revealed coin"),
+        }
+    }
+
+    // A client holding one wallet issued by `server`
+    fn issued_client(server: &Server) -> Client {
+        let mut client = Client::new(server.setup());
+        let issue_request = client.issue_request();
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        client
+    }
 
     #[test]
     fn e2e_dap() {
         // Start server
-        let mut server = Server::new();
+        let server = Server::new(Params { height: 10, mode: Mode::Revealed, bundle: 0 });
 
         // Start client
-        let mut client = Client::new(server.pp.clone());
+        let mut client = Client::new(server.setup());
         
         // Client makes issue request        
         let issue_request = client.issue_request();        
 
         // Server signs issue request and returns issue response
-        let issue_response = server.issue(issue_request);
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
 
         // Client processes issue response
-        client.issue_process(issue_response);
+        client.issue_process(issue_response).unwrap();
 
         // Client precomputes proofs
-        client.precompute_proofs();
+        client.precompute_proofs_for(1, &CTX).unwrap();
 
         // Client makes redeem request
-        let _redeem_request = client.redeem_request(0);
+        let redeem_request = client.redeem_request(1, &CTX).unwrap();
+        assert_eq!(redeem_request.coins.len(), 1);
+        assert!(client.wallet[0].spent[0]);
 
         // Server checks validity of coins
-        // XXX: To update
-        //let redeem_response = server.redeem(redeem_request);
-        //println!("{:?}", redeem_response);
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert!(redeem_response.valid);
+        assert_eq!(redeem_response.value, 1);
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        let mut client = Client::new(server.setup());
+
+        // Nothing to spend before issuance
+        assert!(matches!(client.redeem_request(1, &CTX), Err(DapError::NoWallet)));
+
+        // Commitment that isn't a field element
+        let issue_request = IssueRequest { id: 0, value: 1, com: vec![0xff; 3] };
+        assert!(matches!(server.issue(ADVERTISER, issue_request), Err(DapError::Serialization(_))));
+
+        let issue_request = client.issue_request();
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        assert!(matches!(client.precompute_proofs(&CTX), Err(DapError::NotIssued)));
+
+        // Tampered signature
+        let mut bad_response = issue_response.clone();
+        bad_response.sig.verifier_challenge[0] ^= 1;
+        assert!(matches!(client.issue_process(bad_response), Err(DapError::InvalidSignature)));
+        assert!(client.wallet[0].sig.is_none());
+
+        client.issue_process(issue_response).unwrap();
+        client.precompute_proofs_for(1, &CTX).unwrap();
+        assert!(matches!(client.redeem_request(5, &CTX), Err(DapError::InsufficientFunds(5))));
+        // Only leaf 0 has a proof
+        assert!(matches!(client.redeem_request(2, &CTX), Err(DapError::MissingProof(_))));
+
+        // Truncated proof
+        let mut redeem_request = client.redeem_request(1, &CTX).unwrap();
+        redeem_request.coins[0].proof_bytes.truncate(10);
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::Malformed)]);
+    }
+
+    #[test]
+    fn atomic_redeem() {
+        let server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        let mut client = issued_client(&server);
+        client.precompute_proofs_for(1, &CTX).unwrap();
+        let redeem_request = client.redeem_request(1, &CTX).unwrap();
+        let coin = redeem_request.coins[0].clone();
+
+        // The same coin twice in one request overlaps itself
+        let overlapping = RedeemRequest { publisher: CTX.publisher, coins: vec![coin.clone(), coin.clone()], bundles: vec![] };
+        let redeem_response = server.redeem(overlapping).unwrap();
+        assert!(!redeem_response.valid);
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::Aborted),
+                        CoinResult::Rejected(Rejection::DoubleSpent)]);
+
+        // A bad coin after a good one spends neither
+        let mut bad_coin = coin.clone();
+        bad_coin.denom = 7;
+        let mixed = RedeemRequest { publisher: CTX.publisher, coins: vec![coin.clone(), bad_coin], bundles: vec![] };
+        let redeem_response = server.redeem(mixed).unwrap();
+        assert!(!redeem_response.valid);
+        assert_eq!(redeem_response.value, 0);
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::Aborted),
+                        CoinResult::Rejected(Rejection::UnknownDenomination)]);
+
+        // Neither rejected request left the coin marked as spent
+        let redeem_response = server.redeem(redeem_request.clone()).unwrap();
+        assert!(redeem_response.valid);
+        assert_eq!(redeem_response.value, 1);
+        assert_eq!(redeem_response.coins, vec![CoinResult::Accepted(1)]);
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::DoubleSpent)]);
+    }
+
+    #[test]
+    fn checked_keys() {
+        let mut server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        server.check_keys = true;
+        let mut client = issued_client(&server);
+        client.precompute_proofs(&CTX).unwrap();
+        let redeem_request = client.redeem_request(3, &CTX).unwrap();
+
+        // A key that doesn't expand to the proven node is turned down
+        let mut forged = redeem_request.clone();
+        if let Spend::Revealed { key, .. } = &mut forged.coins[0].spend {
+            key[0] ^= 1;
+        }
+        let redeem_response = server.redeem(forged).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::BadProof),
+                        CoinResult::Rejected(Rejection::Aborted)]);
+
+        // The key of every denomination expands to its node
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Accepted(2), CoinResult::Accepted(1)]);
+    }
+
+    #[test]
+    fn redemption_context() {
+        let server = Server::new(Params { height: 1, mode: Mode::Revealed, bundle: 0 });
+        let mut client = issued_client(&server);
+        client.precompute_proofs(&CTX).unwrap();
+
+        // Proofs are only precomputed for their context
+        let other = Context { publisher: 2, auction: 1 };
+        assert!(matches!(client.redeem_request(1, &other), Err(DapError::MissingProof(_))));
+        let redeem_request = client.redeem_request(1, &CTX).unwrap();
+        assert_eq!(redeem_request.coins[0].context, CTX);
+
+        // Another publisher can't redeem the coin as it is...
+        let mut stolen = redeem_request.clone();
+        stolen.publisher = other.publisher;
+        let redeem_response = server.redeem(stolen.clone()).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::WrongPublisher)]);
+
+        // ...nor made out to itself
+        stolen.coins[0].context = other;
+        let redeem_response = server.redeem(stolen).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::BadProof)]);
+
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Accepted(1)]);
+    }
+
+    #[test]
+    fn nullified_redeem() {
+        let server = Server::new(Params { height: 2, mode: Mode::Nullified, bundle: 0 });
+        let mut client = issued_client(&server);
+        client.precompute_proofs(&CTX).unwrap();
+
+        // Coins carry one nullifier per level down to their node, and no tag
+        let redeem_request = client.redeem_request(3, &CTX).unwrap();
+        let lengths: Vec<(u8, usize)> = redeem_request.coins
+            .iter()
+            .map(|coin| match &coin.spend {
+                Spend::Nullified { nullifiers } => (coin.denom, nullifiers.len()),
+                Spend::Revealed { .. } => panic!("Expected a nullified coin"),
+            })
+            .collect();
+        assert_eq!(lengths, vec![(1, 2), (0, 3)]);
+
+        // A revealed coin isn't accepted by a nullifying exchange
+        let mut revealed = redeem_request.clone();
+        revealed.coins[0].spend = Spend::Revealed {
+            key: [0; 32],
+            tag_bytes: vec![0; Fp::default().serialized_size()],
+            pos: 0,
+            instance_bytes: vec![0; Fp::default().serialized_size()],
+        };
+        let redeem_response = server.redeem(revealed).unwrap();
+        assert_eq!(redeem_response.coins[0], CoinResult::Rejected(Rejection::Malformed));
+
+        let redeem_response = server.redeem(redeem_request.clone()).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Accepted(2), CoinResult::Accepted(1)]);
+
+        // Nullifiers are spent like tags: neither coin can be replayed
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::DoubleSpent),
+                        CoinResult::Rejected(Rejection::DoubleSpent)]);
+    }
+
+    #[test]
+    fn bundled_redeem() {
+        let server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 2 });
+        let mut client = issued_client(&server);
+
+        // Both nodes of the payment are spent under one proof
+        let redeem_request = client.redeem_bundled(3, &CTX).unwrap();
+        assert!(redeem_request.coins.is_empty());
+        assert_eq!(redeem_request.bundles.len(), 1);
+        let nodes: Vec<(u8, u64)> = redeem_request.bundles[0].coins
+            .iter()
+            .map(|(denom, spend)| match spend {
+                Spend::Revealed { pos, .. } => (*denom, *pos),
+                Spend::Nullified { .. } => panic!("Expected a revealed coin"),
+            })
+            .collect();
+        assert_eq!(nodes, vec![(1, 0), (0, 2)]);
+
+        // The proof covers every node of the bundle
+        let mut moved = redeem_request.clone();
+        if let Spend::Revealed { pos, .. } = &mut moved.bundles[0].coins[1].1 {
+            *pos = 3;
+        }
+        let redeem_response = server.redeem(moved).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::BadProof),
+                        CoinResult::Rejected(Rejection::BadProof)]);
+
+        // A bundle must hold at least one node
+        let mut emptied = redeem_request.clone();
+        emptied.bundles.push(emptied.bundles[0].clone());
+        emptied.bundles[1].coins.clear();
+        assert!(matches!(server.redeem(emptied), Err(DapError::BundleSize(0))));
+
+        // A bundle turned down for overlapping an earlier coin leaves its
+        // other nodes free for the rest of the request
+        client.wallet[0].spent = bit_vec::BitVec::from_elem(4, false);
+        let leaf_0 = client.redeem_bundled(1, &CTX).unwrap().bundles.remove(0);
+        client.wallet[0].spent = bit_vec::BitVec::from_fn(4, |i| i < 2);
+        client.precompute_proofs(&CTX).unwrap();
+        let leaf_2 = client.redeem_request(1, &CTX).unwrap().coins.remove(0);
+        let overlapping = RedeemRequest {
+            publisher: CTX.publisher,
+            coins: vec![leaf_2],
+            bundles: vec![redeem_request.bundles[0].clone(), leaf_0],
+        };
+        let redeem_response = server.redeem(overlapping).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::Aborted),
+                        CoinResult::Rejected(Rejection::DoubleSpent),
+                        CoinResult::Rejected(Rejection::DoubleSpent),
+                        CoinResult::Rejected(Rejection::Aborted)]);
+
+        let redeem_response = server.redeem(redeem_request.clone()).unwrap();
+        assert_eq!(redeem_response.value, 3);
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Accepted(2), CoinResult::Accepted(1)]);
+        let redeem_response = server.redeem(redeem_request).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::DoubleSpent),
+                        CoinResult::Rejected(Rejection::DoubleSpent)]);
+
+        // Only the last leaf is left
+        assert!(matches!(client.redeem_bundled(2, &CTX), Err(DapError::InsufficientFunds(2))));
+        let redeem_request = client.redeem_bundled(1, &CTX).unwrap();
+        assert_eq!(redeem_request.bundles[0].coins.len(), 1);
+        assert!(server.redeem(redeem_request).unwrap().valid);
+    }
+
+    #[test]
+    fn partial_wallet() {
+        let server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        let mut client = Client::new(server.setup());
+
+        // Neither the client nor the exchange takes a value the tree can't
+        // hold
+        assert!(matches!(client.issue_request_for(0), Err(DapError::InvalidValue(0))));
+        assert!(matches!(client.issue_request_for(5), Err(DapError::InvalidValue(5))));
+        assert!(client.wallet.is_empty());
+        let mut issue_request = client.issue_request_for(3).unwrap();
+        issue_request.value = 5;
+        assert!(matches!(server.issue(ADVERTISER, issue_request), Err(DapError::InvalidValue(5))));
+
+        let issue_request = client.issue_request_for(3).unwrap();
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        assert_eq!(client.balance(), 3);
+
+        // Only the nodes over the three paid leaves get proofs
+        client.precompute_proofs(&CTX).unwrap();
+        let indices: Vec<u64> = client.wallet[1].proofs.keys().copied().collect();
+        assert_eq!(indices, vec![node_index(1, 0), node_index(2, 0), node_index(2, 1), node_index(2, 2)]);
+
+        // The circuit holds the client to the signed value
+        let pp = server.setup();
+        let entry = &client.wallet[1];
+        let satisfied = |value: u64, depth: usize, pos: u64| {
+            let circuit = SpendC::<2>::new(pp.sig_params.clone(),
+                                           pp.pk,
+                                           entry.sig.clone().unwrap(),
+                                           entry.root,
+                                           entry.com,
+                                           entry.open,
+                                           value,
+                                           entry.tree.node(depth, pos),
+                                           depth,
+                                           pos,
+                                           CTX.to_field(&pp.hasher),
+                                           &entry.tree.path(depth, pos),
+                                           pp.hasher.clone(),
+                                           false);
+            let cs = ConstraintSystem::<Fr>::new_ref();
+            circuit.generate_constraints(cs.clone()).unwrap();
+            cs.is_satisfied().unwrap()
+        };
+        assert!(satisfied(3, 2, 2));
+        assert!(!satisfied(3, 2, 3));
+        assert!(!satisfied(3, 1, 1));
+        assert!(!satisfied(4, 2, 3));
+
+        let redeem_request = client.redeem_request(3, &CTX).unwrap();
+        assert_eq!(server.redeem(redeem_request).unwrap().value, 3);
+        assert!(matches!(client.redeem_request(1, &CTX), Err(DapError::InsufficientFunds(1))));
+    }
+
+    #[test]
+    fn billed_issuance() {
+        let mut server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        let ledger = Arc::new(Ledger::new());
+        server.ledger = Some(ledger.clone());
+        let mut client = Client::new(server.setup());
+
+        // No account, then one that can't cover a full wallet
+        let issue_request = client.issue_request();
+        assert!(matches!(server.issue(7, issue_request.clone()),
+                         Err(DapError::Billing(BillingError::UnknownAccount(7)))));
+        ledger.open(7);
+        ledger.deposit(7, 3).unwrap();
+        assert!(matches!(server.issue(7, issue_request.clone()),
+                         Err(DapError::Billing(BillingError::InsufficientBalance(4)))));
+
+        // A wallet worth less than its tree costs its value
+        let partial_request = client.issue_request_for(3).unwrap();
+        server.issue(7, partial_request).unwrap();
+        assert_eq!(ledger.balance(7).unwrap(), 0);
+
+        ledger.deposit(7, 4).unwrap();
+        let issue_response = server.issue(7, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        let items: Vec<Item> = ledger.statement(7, Period { from: 0, to: u64::MAX }).unwrap().entries
+            .iter()
+            .map(|entry| entry.item)
+            .collect();
+        assert_eq!(items, vec![Item::Deposit, Item::Wallet(3), Item::Deposit, Item::Wallet(4)]);
+
+        // Only the caller's own account is charged
+        let issue_request = client.issue_request();
+        assert!(matches!(server.issue(8, issue_request),
+                         Err(DapError::Billing(BillingError::UnknownAccount(8)))));
+        assert_eq!(ledger.balance(7).unwrap(), 0);
+    }
+
+    #[test]
+    fn publisher_earnings() {
+        let mut server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        let earnings = Arc::new(Earnings::new());
+        server.earnings = Some(earnings.clone());
+        let mut client = issued_client(&server);
+        client.precompute_proofs(&CTX).unwrap();
+
+        let redeem_request = client.redeem_request(1, &CTX).unwrap();
+        server.redeem(redeem_request.clone()).unwrap();
+        // A replay is turned down, and credits nothing
+        assert!(!server.redeem(redeem_request).unwrap().valid);
+        let redeem_request = client.redeem_request(2, &CTX).unwrap();
+        server.redeem(redeem_request).unwrap();
+        assert_eq!(earnings.balance(CTX.publisher), 3);
+
+        let settlement = earnings.settlement(Period { from: 0, to: u64::MAX });
+        assert_eq!(settlement.payouts.len(), 1);
+        assert_eq!(settlement.payouts[0].publisher, CTX.publisher);
+        assert_eq!(settlement.payouts[0].redemptions, 2);
+        assert_eq!(settlement.total, 3);
+        assert!(earnings.history(CTX.publisher).iter().all(|credit| credit.scheme == Scheme::Dap));
+    }
+
+    #[test]
+    fn bounded_redeem() {
+        let mut server = Server::new(Params { height: 1, mode: Mode::Revealed, bundle: 0 });
+        let coin = Coin {
+            epoch: server.setup().epoch,
+            context: CTX,
+            denom: 0,
+            spend: Spend::Revealed {
+                key: [0; 32],
+                tag_bytes: vec![0; Fp::default().serialized_size()],
+                pos: 0,
+                instance_bytes: vec![0; Fp::default().serialized_size()],
+            },
+            proof_bytes: vec![0; GrothProof::default().serialized_size()],
+        };
+
+        // Denomination beyond the wallet height, rejected before expanding it
+        let mut huge = coin.clone();
+        huge.denom = 200;
+        let redeem_response = server.redeem(RedeemRequest { publisher: CTX.publisher, coins: vec![huge] , bundles: vec![] }).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::UnknownDenomination)]);
+
+        // Oversized proof
+        let mut padded = coin.clone();
+        padded.proof_bytes.push(0);
+        let redeem_response = server.redeem(RedeemRequest { publisher: CTX.publisher, coins: vec![padded] , bundles: vec![] }).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::Malformed)]);
+
+        // Too many coins, then too many leaves
+        server.limits = Limits { max_coins: 2, max_leaves: 2 };
+        let redeem_request = RedeemRequest { publisher: CTX.publisher, coins: vec![coin.clone(); 3], bundles: vec![] };
+        assert!(matches!(server.redeem(redeem_request), Err(DapError::RequestTooLarge)));
+        let mut whole = coin.clone();
+        whole.denom = 1;
+        let redeem_request = RedeemRequest { publisher: CTX.publisher, coins: vec![whole, coin], bundles: vec![] };
+        assert!(matches!(server.redeem(redeem_request), Err(DapError::RequestTooLarge)));
+    }
+
+    #[test]
+    fn batch_verify() {
+        let rng = &mut test_rng();
+        let server = Server::new(Params { height: 0, mode: Mode::Revealed, bundle: 0 });
+        let mut client = Client::new(server.setup());
+        for _ in 0..3 {
+            let issue_request = client.issue_request();
+            let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+            client.issue_process(issue_response).unwrap();
+        }
+        client.precompute_proofs(&CTX).unwrap();
+        let redeem_request = client.redeem_request(3, &CTX).unwrap();
+        let pp = server.setup();
+        let mut claims: Vec<Claim> = redeem_request.coins
+            .iter()
+            .map(|coin| {
+                let (tag_bytes, pos, instance_bytes) = revealed(coin);
+                Claim {
+                    inputs: dispatch::inputs(&pp.pk,
+                                             Fp::deserialize(instance_bytes).unwrap(),
+                                             Fp::deserialize(tag_bytes).unwrap(),
+                                             0,
+                                             pos,
+                                             coin.context.to_field(&pp.hasher)),
+                    proof: GrothProof::deserialize(&*coin.proof_bytes).unwrap(),
+                }
+            })
+            .collect();
+        let groth_pvk = &server.groth_pvk;
+        assert!(batch::verify(groth_pvk, &claims, rng).unwrap().is_empty());
+        assert!(batch::verify(groth_pvk, &[], rng).unwrap().is_empty());
+
+        // A proof for another wallet fails the batch, and is singled out
+        claims[1].proof = claims[2].proof.clone();
+        assert_eq!(batch::verify(groth_pvk, &claims, rng).unwrap(), vec![1]);
+
+        // Nor does the server accept it
+        let mut bad_request = redeem_request.clone();
+        bad_request.coins[1].proof_bytes = bad_request.coins[2].proof_bytes.clone();
+        let redeem_response = server.redeem_with_rng(bad_request, rng).unwrap();
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::Aborted),
+                        CoinResult::Rejected(Rejection::BadProof),
+                        CoinResult::Rejected(Rejection::Aborted)]);
+        assert_eq!(server.redeem_with_rng(redeem_request, rng).unwrap().value, 3);
+    }
+
+    #[test]
+    fn save_and_load_keys() {
+        // A directory of this test run's own, removed once it passes
+        let dir = env::temp_dir().join(format!("divtokens_dap_keys_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let server = Server::new(Params { height: 1, mode: Mode::Revealed, bundle: 0 });
+        server.save(&dir).unwrap();
+
+        // A restarted server keeps the issuer key and circuits
+        let restarted = Server::load(&dir,
+                                     EpochPolicy::default(),
+                                     Sharded::new(vec![ExactStore::new()])).unwrap();
+        let keys = server.current();
+        assert_eq!(restarted.current().pp.pk, keys.pp.pk);
+        assert_eq!(restarted.current().started, keys.started);
+        assert_eq!(restarted.groth_vk, server.groth_vk);
+
+        // Only the exchange can read the issuer key
+        #[cfg(unix)]
+        {
+            let epoch_dir = dir.join(keys.pp.epoch.to_string());
+            let mode = fs::metadata(epoch_dir.join(SK_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A client given the saved parameters can spend at either server
+        let mut client = Client::new(PP::load(dir.join(PP_FILE)).unwrap());
+        let issue_request = client.issue_request();
+        let issue_response = restarted.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        client.precompute_proofs(&CTX).unwrap();
+        let redeem_request = client.redeem_request(1, &CTX).unwrap();
+        assert!(server.redeem(redeem_request).unwrap().valid);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn key_epochs() {
+        let server = Server::new(Params { height: 0, mode: Mode::Revealed, bundle: 0 });
+        let mut client = Client::new(server.setup());
+        let first = server.setup().epoch;
+        for _ in 0..2 {
+            let issue_request = client.issue_request();
+            let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+            client.issue_process(issue_response).unwrap();
+        }
+        client.precompute_proofs(&CTX).unwrap();
+        let old_request = client.redeem_request(1, &CTX).unwrap();
+        let expired_request = client.redeem_request(1, &CTX).unwrap();
+        assert_eq!(old_request.coins[0].epoch, first);
+
+        // Wallets are issued under the new key once the epoch rotates, but
+        // with the same circuits
+        let second = server.rotate().unwrap();
+        assert_eq!(second, first + 1);
+        assert_ne!(server.setup().pk, client.pp.pk);
+        assert!(Arc::ptr_eq(&server.setup().groth_pk, &client.pp.groth_pk));
+        let issue_request = client.issue_request();
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        assert_eq!(issue_response.epoch, second);
+        assert!(matches!(client.issue_process(issue_response.clone()),
+                         Err(DapError::UnknownEpoch(e)) if e == second));
+        client.update(server.setup());
+        client.issue_process(issue_response).unwrap();
+        client.precompute_proofs(&CTX).unwrap();
+
+        // Coins of the previous epoch are still redeemed
+        assert!(server.redeem(old_request.clone()).unwrap().valid);
+        assert!(server.redeem(client.redeem_request(1, &CTX).unwrap()).unwrap().valid);
+
+        // Closing the first epoch turns its coins down and forgets its spends
+        server.rotate().unwrap();
+        assert!(server.epoch(first).is_none());
+        let redeem_response = server.redeem(expired_request).unwrap();
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::WrongEpoch)]);
+        let path = index::path(revealed(&old_request.coins[0]).0, 0, 0);
+        let guards = server.spent.lock(&[&path[0]]);
+        assert!(!index::overlaps_path(guards.get(&path[0]), first, &path));
+    }
+
+    #[test]
+    fn epoch_policy() {
+        let params = Params { height: 0, mode: Mode::Revealed, bundle: 0 };
+
+        // Epochs count up from 0 however long they last
+        let weekly = EpochPolicy { length: 7 * 24 * 60 * 60, open: 2 };
+        let server = Server::with_store(params, weekly, Sharded::new(vec![ExactStore::new()]));
+        assert_eq!(server.setup().epoch, 0);
+        assert_eq!(server.tick().unwrap(), None);
+        assert_eq!(server.rotate().unwrap(), 1);
+        assert_eq!(server.tick().unwrap(), None);
+
+        // An epoch is over once its length has passed since it started
+        let started = 1_000_000;
+        assert!(!weekly.is_over(started, started));
+        assert!(!weekly.is_over(started, started + weekly.length - 1));
+        assert!(weekly.is_over(started, started + weekly.length));
+        // Even a zero-length epoch lasts a second
+        let brief = EpochPolicy { length: 0, open: 2 };
+        assert!(!brief.is_over(started, started));
+        assert!(brief.is_over(started, started + 1));
+    }
+
+    #[test]
+    fn injectable_rng() {
+        let server = Server::with_rng(Params { height: 0, mode: Mode::Revealed, bundle: 0 },
+                                      EpochPolicy::default(),
+                                      Sharded::new(vec![ExactStore::new()]),
+                                      &mut test_rng());
+
+        // Seeded clients build the same wallet
+        let mut client_a = Client::new(server.setup());
+        let mut client_b = Client::new(server.setup());
+        let issue_request = client_a.issue_request_with_rng(&mut test_rng());
+        assert_eq!(issue_request.com, client_b.issue_request_with_rng(&mut test_rng()).com);
+
+        // By default wallets and signing nonces are fresh
+        assert_ne!(client_a.issue_request().com, client_b.issue_request().com);
+        let sig_1 = server.issue(ADVERTISER, issue_request.clone()).unwrap().sig;
+        let sig_2 = server.issue(ADVERTISER, issue_request).unwrap().sig;
+        assert_ne!(sig_1.prover_response, sig_2.prover_response);
+    }
+
+    #[test]
+    fn allocate_nodes() {
+        let mut spent = bit_vec::BitVec::from_elem(16, false);
+
+        // 11 = 8 + 2 + 1
+        let nodes = allocate(&[spent.clone()], 4, 11).unwrap();
+        assert_eq!(nodes, vec![(0, 1, 0), (0, 3, 4), (0, 4, 10)]);
+
+        // Only 5 leaves left after spending 11
+        for i in 0..11 {
+            spent.set(i, true);
+        }
+        assert!(allocate(&[spent.clone()], 4, 6).is_none());
+        assert_eq!(allocate(&[spent.clone()], 4, 5).unwrap(), vec![(0, 2, 3), (0, 4, 11)]);
+        assert!(allocate(&[spent.clone()], 4, 17).is_none());
+
+        // A second wallet makes up the difference
+        let fresh = bit_vec::BitVec::from_elem(16, false);
+        assert_eq!(allocate(&[spent.clone(), fresh.clone()], 4, 6).unwrap(),
+                   vec![(0, 2, 3), (1, 3, 0)]);
+        assert_eq!(allocate(&[spent, fresh], 4, 21).unwrap(),
+                   vec![(1, 0, 0), (0, 2, 3), (0, 4, 11)]);
+    }
+
+    #[test]
+    fn subtree_proofs() {
+        let server = Server::new(Params { height: 2, mode: Mode::Revealed, bundle: 0 });
+        let mut client = issued_client(&server);
+
+        // One proof per node of the wallet, internal ones included
+        client.precompute_proofs(&CTX).unwrap();
+        assert_eq!(client.wallet[0].proofs.len(), 7);
+
+        // 3 = 2 + 1 spends the left subtree and leaf 2, leaving only leaf 3
+        let redeem_request = client.redeem_request(3, &CTX).unwrap();
+        let spent: Vec<(u8, u64)> = redeem_request.coins
+            .iter()
+            .map(|coin| (coin.denom, revealed(coin).1))
+            .collect();
+        assert_eq!(spent, vec![(1, 0), (0, 2)]);
+        let left: Vec<u64> = client.wallet[0].proofs.keys().copied().collect();
+        assert_eq!(left, vec![node_index(2, 3)]);
+        assert_eq!(server.redeem(redeem_request).unwrap().value, 3);
+        assert_eq!(server.redeem(client.redeem_request(1, &CTX).unwrap()).unwrap().value, 1);
+
+        // Proving ahead for a single payment only covers its nodes
+        let issue_request = client.issue_request();
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        client.precompute_proofs_for(2, &CTX).unwrap();
+        let left: Vec<u64> = client.wallet[1].proofs.keys().copied().collect();
+        assert_eq!(left, vec![node_index(1, 0)]);
+        assert!(matches!(client.precompute_proofs_for(5, &CTX),
+                         Err(DapError::InsufficientFunds(5))));
+    }
+
+    #[test]
+    fn multiple_wallets() {
+        let server = Server::new(Params { height: 0, mode: Mode::Revealed, bundle: 0 });
+        let mut client = Client::new(server.setup());
+        let issue_request_a = client.issue_request();
+        let issue_request_b = client.issue_request();
+        assert_ne!(issue_request_a.id, issue_request_b.id);
+
+        // Responses are matched to wallets by ID, in any order
+        let issue_response_a = server.issue(ADVERTISER, issue_request_a).unwrap();
+        let issue_response_b = server.issue(ADVERTISER, issue_request_b).unwrap();
+        let mut swapped = issue_response_b.clone();
+        swapped.id = issue_response_a.id;
+        assert!(matches!(client.issue_process(swapped), Err(DapError::InvalidSignature)));
+        let mut stray = issue_response_b.clone();
+        stray.id = 99;
+        assert!(matches!(client.issue_process(stray), Err(DapError::UnknownRequest(99))));
+        client.issue_process(issue_response_b).unwrap();
+        assert!(client.wallet[0].sig.is_none());
+        assert_eq!(client.balance(), 1);
+        client.issue_process(issue_response_a).unwrap();
+        assert_eq!(client.balance(), 2);
+
+        // One payment spends from both wallets
+        client.precompute_proofs(&CTX).unwrap();
+        let redeem_request = client.redeem_request(2, &CTX).unwrap();
+        assert_eq!(redeem_request.coins.len(), 2);
+        assert_eq!(client.balance(), 0);
+        assert!(server.redeem(redeem_request).unwrap().valid);
+    }
+
+    #[test]
+    fn concurrent_redeem() {
+        let server = Arc::new(Server::new(Params { height: 0, mode: Mode::Revealed, bundle: 0 }));
+        let mut client = issued_client(&server);
+        client.precompute_proofs(&CTX).unwrap();
+        let redeem_request = client.redeem_request(1, &CTX).unwrap();
+
+        // Racing redemptions of one coin: exactly one is credited
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let server = server.clone();
+                let redeem_request = redeem_request.clone();
+                thread::spawn(move || server.redeem(redeem_request).unwrap().valid)
+            })
+            .collect();
+        let credited = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&valid| valid)
+            .count();
+        assert_eq!(credited, 1);
+    }
+
+    #[test]
+    fn merkle_tree_matches_smt() {
+        let params = setup_params(Curve::Bls381, POSEIDON_EXP, POSEIDON_WIDTH);
+        let hasher = Poseidon::<Fr> { params };
+
+        let key = rand::thread_rng().gen::<[u8; 32]>();
+        let ggm = GGM::new();
+        let leaves: Vec<Fp> = ggm.expand(&key, 4)
+            .iter()
+            .map(|bytes| Fp256::from_le_bytes_mod_order(bytes))
+            .collect();
+        let smt = SMT::<4>::new_sequential(&leaves, &hasher, &DEFAULT_LEAF).unwrap();
+        let tree = MerkleTree::new(&hasher, &leaves).unwrap();
+        assert_eq!(tree.root(), smt.root());
+
+        // The constrained key of node (2, 1) expands to the subtree under it
+        let c_key = ggm.eval(&key, &u16_to_bv(1, 2));
+        let sub_leaves: Vec<Fp> = ggm.expand(&c_key, 2)
+            .iter()
+            .map(|bytes| Fp256::from_le_bytes_mod_order(bytes))
+            .collect();
+        assert_eq!(sub_leaves, leaves[4..8].to_vec());
+        assert_eq!(subtree_root(&hasher, &sub_leaves).unwrap(),
+                   smt.tree[&node_index(2, 1)]);
+        assert_eq!(tree.node(2, 1), smt.tree[&node_index(2, 1)]);
+        assert!(MerkleTree::new(&hasher, &leaves[..3]).is_err());
+    }
+
+    #[test]
+    fn spent_index() {
+        let mut store = ExactStore::new();
+        let (tag, other) = ([1u8; 32], [2u8; 32]);
+        index::mark(&mut store, 7, &tag, 1, 0).unwrap();
+
+        // Descendants and ancestors of a spent node overlap it, siblings don't
+        assert!(index::overlaps(&store, 7, &tag, 1, 0));
+        assert!(index::overlaps(&store, 7, &tag, 3, 1));
+        assert!(index::overlaps(&store, 7, &tag, 0, 0));
+        assert!(!index::overlaps(&store, 7, &tag, 1, 1));
+        assert!(!index::overlaps(&store, 7, &tag, 2, 2));
+
+        // Wallets and epochs are indexed separately
+        assert!(!index::overlaps(&store, 7, &other, 1, 0));
+        assert!(!index::overlaps(&store, 8, &tag, 1, 0));
+        index::mark(&mut store, 8, &tag, 2, 3).unwrap();
+        store.remove_prefix(&index::epoch_prefix(7)).unwrap();
+        assert!(!index::overlaps(&store, 7, &tag, 1, 0));
+        assert!(index::overlaps(&store, 8, &tag, 1, 1));
     }
 
     #[test]
@@ -76,39 +837,29 @@ mod test {
 
         const ITERS: u32 = 100;        
         
-        macro_rules! mk_bench {
-            ($server:ident, $client: ident, $n: literal) => {
-                // Start server
-                let server = $server::new();
-
-                // Start client
-                let mut client = $client::new(server.pp.clone());
-
-                // Benchmark issue request
-                let mut duration = Duration::new(0, 0);
-                for _ in 0..ITERS {
-                    let start = Instant::now();
-                    client.issue_request();
-                    duration += start.elapsed();
-                    // Reset wallet
-                    client.wallet.pop();
-                }
-                let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
-                let size = 2_u32.pow($n);
-                let issue_request = client.issue_request();
-                let num_bytes = bincode::serialize(&issue_request).unwrap().len();
-                comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
-                comm_file.write_all(format!("{} {}\n", size, num_bytes).as_bytes()).unwrap();
+        for n in 6..=12 {
+            // Start server
+            let server = Server::new(Params { height: n, mode: Mode::Revealed, bundle: 0 });
+
+            // Start client
+            let mut client = Client::new(server.setup());
+
+            // Benchmark issue request
+            let mut duration = Duration::new(0, 0);
+            for _ in 0..ITERS {
+                let start = Instant::now();
+                client.issue_request();
+                duration += start.elapsed();
+                // Reset wallet
+                client.wallet.pop();
             }
+            let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
+            let size = 2_u32.pow(n as u32);
+            let issue_request = client.issue_request();
+            let num_bytes = bincode::serialize(&issue_request).unwrap().len();
+            comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
+            comm_file.write_all(format!("{} {}\n", size, num_bytes).as_bytes()).unwrap();
         }
-        
-        mk_bench![Server6 , Client6 , 6 ];
-        mk_bench![Server7 , Client7 , 7 ];
-        mk_bench![Server8 , Client8 , 8 ];
-        mk_bench![Server9 , Client9 , 9 ];
-        mk_bench![Server10, Client10, 10];
-        mk_bench![Server11, Client11, 11];
-        mk_bench![Server12, Client12, 12];
     }
 
     #[test]
@@ -126,42 +877,32 @@ mod test {
 
         const ITERS: u32 = 100;        
         
-        macro_rules! mk_bench {
-            ($server:ident, $client: ident, $n: literal) => {
-                // Start server
-                let mut server = $server::new();
-
-                // Start client
-                let mut client = $client::new(server.pp.clone());
-
-                // Client makes issue request
-                let issue_request = client.issue_request();                
-
-                // Benchmark issue
-                let mut duration = Duration::new(0, 0);
-                for _ in 0..ITERS {
-                    let issue_request = issue_request.clone();
-                    let start = Instant::now();
-                    server.issue(issue_request);
-                    duration += start.elapsed();
-                }
-                // XXX: Maybe as nanos
-                let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
-                let size = 2_u32.pow($n);
-                let issue_response = server.issue(issue_request.clone());
-                let num_bytes = bincode::serialize(&issue_response).unwrap().len();
-                comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
-                comm_file.write_all(format!("{} {}\n", size, num_bytes).as_bytes()).unwrap();
+        for n in 6..=12 {
+            // Start server
+            let server = Server::new(Params { height: n, mode: Mode::Revealed, bundle: 0 });
+
+            // Start client
+            let mut client = Client::new(server.setup());
+
+            // Client makes issue request
+            let issue_request = client.issue_request();                
+
+            // Benchmark issue
+            let mut duration = Duration::new(0, 0);
+            for _ in 0..ITERS {
+                let issue_request = issue_request.clone();
+                let start = Instant::now();
+                server.issue(ADVERTISER, issue_request).unwrap();
+                duration += start.elapsed();
             }
+            // XXX: Maybe as nanos
+            let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
+            let size = 2_u32.pow(n as u32);
+            let issue_response = server.issue(ADVERTISER, issue_request.clone()).unwrap();
+            let num_bytes = bincode::serialize(&issue_response).unwrap().len();
+            comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
+            comm_file.write_all(format!("{} {}\n", size, num_bytes).as_bytes()).unwrap();
         }
-        
-        mk_bench![Server6 , Client6 , 6 ];
-        mk_bench![Server7 , Client7 , 7 ];
-        mk_bench![Server8 , Client8 , 8 ];
-        mk_bench![Server9 , Client9 , 9 ];
-        mk_bench![Server10, Client10, 10];
-        mk_bench![Server11, Client11, 11];
-        mk_bench![Server12, Client12, 12];
     }    
 
     #[test]
@@ -175,41 +916,31 @@ mod test {
 
         const ITERS: u32 = 100;
         
-        macro_rules! mk_bench {
-            ($server:ident, $client: ident, $n: literal) => {
-                // Start server
-                let mut server = $server::new();
-
-                // Start client
-                let mut client = $client::new(server.pp.clone());
-
-                // Client makes issue request        
-                let issue_request = client.issue_request();        
-
-                // Server signs issue request and returns issue response
-                let issue_response = server.issue(issue_request);
-                
-                // Benchmark issue process
-                let mut duration = Duration::new(0, 0);
-                for _ in 0..ITERS {
-                    let start = Instant::now();
-                    client.issue_process(issue_response.clone());
-                    duration += start.elapsed();
-                }
-                // XXX: Maybe as nanos
-                let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
-                let size = 2_u32.pow($n);
-                comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
+        for n in 6..=12 {
+            // Start server
+            let server = Server::new(Params { height: n, mode: Mode::Revealed, bundle: 0 });
+
+            // Start client
+            let mut client = Client::new(server.setup());
+
+            // Client makes issue request        
+            let issue_request = client.issue_request();        
+
+            // Server signs issue request and returns issue response
+            let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+            
+            // Benchmark issue process
+            let mut duration = Duration::new(0, 0);
+            for _ in 0..ITERS {
+                let start = Instant::now();
+                client.issue_process(issue_response.clone()).unwrap();
+                duration += start.elapsed();
             }
+            // XXX: Maybe as nanos
+            let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
+            let size = 2_u32.pow(n as u32);
+            comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
         }
-        
-        mk_bench![Server6 , Client6 , 6 ];
-        mk_bench![Server7 , Client7 , 7 ];
-        mk_bench![Server8 , Client8 , 8 ];
-        mk_bench![Server9 , Client9 , 9 ];
-        mk_bench![Server10, Client10, 10];
-        mk_bench![Server11, Client11, 11];
-        mk_bench![Server12, Client12, 12];
     }
 
     #[test]
@@ -223,50 +954,34 @@ mod test {
 
         const ITERS: u32 = 20;
         
-        macro_rules! mk_bench {
-            ($server:ident, $client: ident, $n: literal) => {
-                // Start server
-                let mut server = $server::new();
-
-                // Start client
-                let mut client = $client::new(server.pp.clone());
-
-                // Client makes issue request        
-                let issue_request = client.issue_request();        
-
-                // Server signs issue request and returns issue response
-                let issue_response = server.issue(issue_request);
-                client.issue_process(issue_response);
-                
-                // Benchmark precompute proofs
-                let mut duration = Duration::new(0, 0);
-                for _ in 0..ITERS {
-                    let start = Instant::now();
-                    client.precompute_proofs();
-                    duration += start.elapsed();
-                    // Reset state
-                    client.wallet[0].coins = vec![];
-                }
-                let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
-                //let size = 2_u32.pow(12 - $n);
-                let size = 2_u32.pow($n);
-                comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
+        for n in 6..=12 {
+            // Start server
+            let server = Server::new(Params { height: n, mode: Mode::Revealed, bundle: 0 });
+
+            // Start client
+            let mut client = Client::new(server.setup());
+
+            // Client makes issue request        
+            let issue_request = client.issue_request();        
+
+            // Server signs issue request and returns issue response
+            let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+            client.issue_process(issue_response).unwrap();
+            
+            // Benchmark precompute proofs
+            let mut duration = Duration::new(0, 0);
+            for _ in 0..ITERS {
+                let start = Instant::now();
+                client.precompute_proofs_for(1, &CTX).unwrap();
+                duration += start.elapsed();
+                // Reset state
+                client.wallet[0].proofs.clear();
             }
+            let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
+            //let size = 2_u32.pow(12 - n as u32);
+            let size = 2_u32.pow(n as u32);
+            comp_file.write_all(format!("{} {}\n", size, avg_duration).as_bytes()).unwrap();
         }
-
-        //mk_bench![Server0 , Client0 , 0 ];
-        //mk_bench![Server1 , Client1 , 1 ];
-        //mk_bench![Server2 , Client2 , 2 ];
-        //mk_bench![Server3 , Client3 , 3 ];
-        //mk_bench![Server4 , Client4 , 4 ];
-        //mk_bench![Server5 , Client5 , 5 ];        
-        mk_bench![Server6 , Client6 , 6 ];
-        mk_bench![Server7 , Client7 , 7 ];
-        mk_bench![Server8 , Client8 , 8 ];
-        mk_bench![Server9 , Client9 , 9 ];
-        mk_bench![Server10, Client10, 10];
-        mk_bench![Server11, Client11, 11];
-        mk_bench![Server12, Client12, 12];
     }
 
     #[test]    
@@ -278,20 +993,20 @@ mod test {
         comm_file.write_all(b"# Value vs. communication (bytes)\n").unwrap();
 
         // Start server
-        let mut server = Server::new();
+        let server = Server::new(Params { height: 10, mode: Mode::Revealed, bundle: 0 });
 
         // Start client
-        let mut client = Client::new(server.pp.clone());
+        let mut client = Client::new(server.setup());
 
         // Client makes issue request        
         let issue_request = client.issue_request();        
 
         // Server signs issue request and returns issue response
-        let issue_response = server.issue(issue_request);
-        client.issue_process(issue_response);
-        client.precompute_proofs();
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        client.precompute_proofs_for(1, &CTX).unwrap();
 
-        let mut redeem_request = client.redeem_request(0);
+        let mut redeem_request = client.redeem_request(1, &CTX).unwrap();
         let coin = redeem_request.coins[0].clone();
         let coin_bytes = bincode::serialize(&coin).unwrap().len();
         redeem_request.coins = vec![];
@@ -313,128 +1028,88 @@ mod test {
         comp_file.write_all(b"# Redeem computation\n").unwrap();
         comp_file.write_all(b"# Value vs. CPU time (ms)\n").unwrap();
     
-        let mut server = Server12::new();
+        const HEIGHT: usize = 12;
+        let server = Server::new(Params { height: HEIGHT, mode: Mode::Revealed, bundle: 0 });
+        let keys = server.current();
 
         let mut coins = vec![];        
-        for lvl in 0..=HEIGHT12 {
+        for lvl in 0..=HEIGHT {
             // Expand constrained PRF to generate Merkle tree leaves 
             let key = rand::thread_rng().gen::<[u8; 32]>();
             let ggm = GGM::new();
-            let leaves_bytes = ggm.expand(&key, (HEIGHT12).try_into().unwrap());
+            let leaves_bytes = ggm.expand(&key, HEIGHT as u8);
             let mut leaves: Vec<Fp> = vec![];
             for bytes in leaves_bytes {
                 leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
             }
     
-            let smt = SMT12::new_sequential(&leaves,
-                                            &server.pp.hasher,
-                                            &DEFAULT_LEAF).unwrap();
-            //let true_root = smt.root();
-            let root = smt.root();            
-
-            let node_index: u64 = ((1u64 << HEIGHT12) - 1).try_into().unwrap();
-            let instance = smt.tree[&node_index];
-            //
-            //let mut internals: Vec<Fp> = vec![];
-            //for i in 0..(1u64 << lvl) {
-            //    internals.push(smt.tree[&(node_index + i)]);
-            //}
-            //
+            let tree = MerkleTree::new(&keys.pp.hasher, &leaves).unwrap();
+            let root = tree.root();
+            let tag = keys.pp.hasher.hash(&[root]).unwrap();
+
+            // Spend the leftmost node at depth lvl
+            let instance = tree.node(lvl, 0);
+            let path = tree.path(lvl, 0);
+
+            // Generate commitment to the root
             let rng = &mut test_rng();
-            macro_rules! make_coin {
-                ($smt2:ident, $circ2:ident, $smt3:ident) => {
-                    {
-                        //let smt_upper = $smt2::new_sequential(&internals,
-                        //                                      &server.pp.hasher,
-                        //                                      &DEFAULT_LEAF).unwrap();
-                        //let root = smt_upper.root();
-                        //assert_eq!(root, true_root);
-            
-                        // Generate path for membership proof of leaf with label 0
-                        //let path = smt_upper.generate_membership_proof(0);
-                        let path = smt.generate_membership_proof(0);
-                        
-                        // Generate commitment to the root
-                        let open = Fr::rand(rng);
-                        let com = server.pp.hasher.hash(&[root, open]).unwrap();
-                        
-                        // Generate a signature on com under pk
-                        let sig = SchnorrJ::sign(&server.pp.sig_params,
-                                                 &server.sk,
-                                                 &com,
-                                                 rng).unwrap();
-                        assert!(SchnorrJ::verify(&server.pp.sig_params,
-                                                 &server.pp.pk,
-                                                 &com,
-                                                 &sig).unwrap());
-                        
-                        let circuit = C12::new(server.pp.sig_params.clone(),
-                                                  server.pp.pk,
-                                                  sig,
-                                                  root,
-                                                  com,
-                                                  open,
-                                                  instance,
-                                                  path,
-                                                  server.pp.hasher.clone());
+            let open = Fr::rand(rng);
+            let com = keys.pp.hasher.hash(&[root, open]).unwrap();
             
-                        let proof = GrothSetup::prove(&server.pp.groth_pks[HEIGHT12],
-                                                      circuit,
-                                                      rng).unwrap();
+            // Generate a signature on com and the full value under pk
+            let value = 1u64 << HEIGHT;
+            let msg = circuit::signed_message(&keys.pp.hasher, com, value).unwrap();
+            let sig = SchnorrJ::sign(&keys.pp.sig_params,
+                                     &keys.sk,
+                                     &msg,
+                                     rng).unwrap();
+            assert!(SchnorrJ::verify(&keys.pp.sig_params,
+                                     &keys.pp.pk,
+                                     &msg,
+                                     &sig).unwrap());
+
+            let witness = dispatch::Witness { sig: &sig, root, com, open, value };
+            let proof = dispatch::prove(&keys.pp,
+                                        &witness,
+                                        CTX.to_field(&keys.pp.hasher),
+                                        (instance, lvl, 0, path),
+                                        rng).unwrap();
             
-                        // Verify proof for leaf 0
-                        let res = GrothSetup::verify(
-                            &server.groth_vks[HEIGHT12],
-                            &vec![instance],
-                            &proof)
-                            .unwrap();
-                        assert!(res);
+            // Verify proof for the node
+            let res = GrothSetup::verify(
+                &server.groth_vk,
+                &dispatch::inputs(&keys.pp.pk,
+                                  instance,
+                                  tag,
+                                  lvl,
+                                  0,
+                                  CTX.to_field(&keys.pp.hasher)),
+                &proof)
+                .unwrap();
+            assert!(res);
             
-                        let mut proof_bytes = vec![];
-                        proof.serialize(&mut proof_bytes).unwrap();
-                        let mut instance_bytes = vec![];
-                        instance.serialize(&mut instance_bytes).unwrap();
+            let mut proof_bytes = vec![];
+            proof.serialize(&mut proof_bytes).unwrap();
+            let mut instance_bytes = vec![];
+            instance.serialize(&mut instance_bytes).unwrap();
+            let mut tag_bytes = vec![];
+            tag.serialize(&mut tag_bytes).unwrap();
             
-                        let label = u16_to_bv(0, lvl);
-                        let c_key = ggm.eval(&key, &label);
+            let label = u16_to_bv(0, lvl);
+            let c_key = ggm.eval(&key, &label);
             
-                        //let new_leaves_bytes = ggm.expand(&c_key, (HEIGHT12 - lvl).try_into().unwrap());
-                        //let mut new_leaves: Vec<Fp> = vec![];
-                        //for bytes in new_leaves_bytes {
-                        //    new_leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
-                        //}
-                        //let smt_lower = $smt3::new_sequential(&new_leaves,
-                        //                                      &server.pp.hasher,
-                        //                                      &DEFAULT_LEAF).unwrap();
-                        //let lower_root = smt_lower.root();
-                        //assert_eq!(lower_root, instance);
-                        
-                        let coin = Coin {
-                            denom: ((HEIGHT12 - lvl) as u8),
-                            key: c_key,
-                            instance_bytes,
-                            proof_bytes,
-                        };
-                        coins.push(coin);
-                    }
-                }
-            }
-            match lvl {
-                0  => make_coin![SMT0 , C0 , SMT12],
-                1  => make_coin![SMT1 , C1 , SMT11],
-                2  => make_coin![SMT2 , C2 , SMT10],
-                3  => make_coin![SMT3 , C3 , SMT9 ],
-                4  => make_coin![SMT4 , C4 , SMT8 ],
-                5  => make_coin![SMT5 , C5 , SMT7 ],
-                6  => make_coin![SMT6 , C6 , SMT6 ],
-                7  => make_coin![SMT7 , C7 , SMT5 ],
-                8  => make_coin![SMT8 , C8 , SMT4 ],
-                9  => make_coin![SMT9 , C9 , SMT3 ],
-                10 => make_coin![SMT10, C10, SMT2 ],
-                11 => make_coin![SMT11, C11, SMT1 ],
-                12 => make_coin![SMT12, C12, SMT0 ],
-                _ => panic!("Shouldn't reach this case!"),
-            };
+            coins.push(Coin {
+                epoch: keys.pp.epoch,
+                context: CTX,
+                denom: ((HEIGHT - lvl) as u8),
+                spend: Spend::Revealed {
+                    key: c_key,
+                    tag_bytes,
+                    pos: 0,
+                    instance_bytes,
+                },
+                proof_bytes,
+            });
         }
 
         //const ITERS: u32 = 100;
@@ -476,16 +1151,16 @@ mod test {
                 }
             }
             
-            let redeem_request = RedeemRequest { coins: redeem_coins };
+            let redeem_request = RedeemRequest { publisher: CTX.publisher, coins: redeem_coins, bundles: vec![] };
         
             let mut duration = Duration::new(0, 0);
             for _ in 0..ITERS {
                 let redeem_request = redeem_request.clone();
                 let start = Instant::now();
-                let redeem_response = server.redeem(redeem_request);
+                let redeem_response = server.redeem(redeem_request).unwrap();
                 duration += start.elapsed();
                 //server.bloom.clear();
-                server.hset.clear();
+                server.spent.clear().unwrap();
                 assert!(redeem_response.valid);                
             }
             let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
@@ -495,6 +1170,7 @@ mod test {
 
     #[test]    
     fn spend_circuit() {
+        const HEIGHT: usize = 10;
         let rng = &mut test_rng();
         let params = setup_params(Curve::Bls381, POSEIDON_EXP, POSEIDON_WIDTH);
         let hasher = Poseidon::<Fr> { params };
@@ -503,7 +1179,7 @@ mod test {
         let start = Instant::now();
         let key = rand::thread_rng().gen::<[u8; 32]>();
         let ggm = GGM::new();
-        let leaves_bytes = ggm.expand(&key, (HEIGHT).try_into().unwrap());
+        let leaves_bytes = ggm.expand(&key, HEIGHT as u8);
         let mut leaves = Vec::new();
         for bytes in leaves_bytes {
             leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
@@ -512,149 +1188,121 @@ mod test {
         
         // Construct Merkle tree and hash to root
         let start = Instant::now();
-        let smt = SMT::new_sequential(&leaves, &hasher, &DEFAULT_LEAF).unwrap();
-        let root = smt.root();
-        
+        let tree = MerkleTree::new(&hasher, &leaves).unwrap();
+        let root = tree.root();
+        let tag = hasher.hash(&[root]).unwrap();
         println!("Compute Merkle root: {:?}", start.elapsed());
-        // Generate path for membership proof of leaf with label 0
-        let path = smt.generate_membership_proof(0);
         
         // Generate commitment to the root
         let open = Fr::rand(rng);
         let com = hasher.hash(&[root, open]).unwrap();
         
-        // Generate a signature on com under pk
+        // Generate a signature on com and the full value under pk
+        let value = 1u64 << HEIGHT;
+        let msg = circuit::signed_message(&hasher, com, value).unwrap();
         let sig_params = SchnorrJ::setup::<_>(rng).unwrap();
         let (pk, sk) = SchnorrJ::keygen(&sig_params, rng).unwrap();
-        let sig = SchnorrJ::sign(&sig_params, &sk, &com, rng).unwrap();
-        assert!(SchnorrJ::verify(&sig_params, &pk, &com, &sig).unwrap());
+        let sig = SchnorrJ::sign(&sig_params, &sk, &msg, rng).unwrap();
+        assert!(SchnorrJ::verify(&sig_params, &pk, &msg, &sig).unwrap());
+
+        // Bind the proof to a redemption context
+        let ctx = CTX.to_field(&hasher);
         
         // Run trusted setup for circuit
         let start = Instant::now();
-        let setup_circuit = SpendC::new(sig_params.clone(),
-                                        pk,
-                                        sig.clone(),
-                                        root,
-                                        com,
-                                        open,
-                                        leaves[0],
-                                        path.clone(),
-                                        hasher.clone()); 
+        let setup_circuit = SpendC::<HEIGHT>::new(sig_params.clone(),
+                                                  pk,
+                                                  sig.clone(),
+                                                  root,
+                                                  com,
+                                                  open,
+                                                  value,
+                                                  leaves[0],
+                                                  HEIGHT,
+                                                  0,
+                                                  ctx,
+                                                  &tree.path(HEIGHT, 0),
+                                                  hasher.clone(),
+                                                  false); 
         let (groth_pk, groth_vk) = GrothSetup::circuit_specific_setup(
             setup_circuit,
             rng)
             .unwrap();
         println!("Trusted setup: {:?}", start.elapsed());
-        
-        // Generate proof for leaf 0
-        let start = Instant::now();
-        let circuit = SpendC::new(sig_params,
-                                  pk,
-                                  sig,
-                                  root,
-                                  com,
-                                  open,
-                                  leaves[0],
-                                  path,
-                                  hasher);
+
+        // The same keys prove nodes at every depth: the leaf 0, a node in
+        // the middle and the root
+        for (depth, pos) in [(HEIGHT, 0), (4, 5), (0, 0)] {
+            let node = tree.node(depth, pos);
+            let start = Instant::now();
+            let circuit = SpendC::<HEIGHT>::new(sig_params.clone(),
+                                                pk,
+                                                sig.clone(),
+                                                root,
+                                                com,
+                                                open,
+                                                value,
+                                                node,
+                                                depth,
+                                                pos,
+                                                ctx,
+                                                &tree.path(depth, pos),
+                                                hasher.clone(),
+                                                false);
+            let proof = GrothSetup::prove(&groth_pk, circuit, rng).unwrap();
+            println!("Prover: {:?}", start.elapsed());        
+
+            let start = Instant::now();                        
+            let inputs = vec![pk.x, pk.y, ctx, tag, node, Fp::from(pos), Fp::from(depth as u64)];
+            let res = GrothSetup::verify(&groth_vk, &inputs, &proof).unwrap();
+            println!("Verifier: {:?}", start.elapsed());                
+            assert!(res);
+
+            // The proof claims its node's depth...
+            if depth > 0 {
+                let mut shallower = inputs.clone();
+                shallower[6] = Fp::from(depth as u64 - 1);
+                assert!(!GrothSetup::verify(&groth_vk, &shallower, &proof).unwrap());
+            }
+        }
+
+        // ...as well as its node, issuer key and context
+        let circuit = SpendC::<HEIGHT>::new(sig_params.clone(),
+                                            pk,
+                                            sig,
+                                            root,
+                                            com,
+                                            open,
+                                            value,
+                                            leaves[0],
+                                            HEIGHT,
+                                            0,
+                                            ctx,
+                                            &tree.path(HEIGHT, 0),
+                                            hasher.clone(),
+                                            false);
         let proof = GrothSetup::prove(&groth_pk, circuit, rng).unwrap();
-        println!("Prover: {:?}", start.elapsed());        
-        
-        // Verify proof for leaf 0
-        let start = Instant::now();                        
+        let depth = Fp::from(HEIGHT as u64);
         let res = GrothSetup::verify(
             &groth_vk,
-            &vec![leaves[0]],
+            &vec![pk.x, pk.y, ctx, tag, leaves[2], Fp::from(2u64), depth],
             &proof)
             .unwrap();
-        println!("Verifier: {:?}", start.elapsed());                
-        assert!(res);
-        
-        // Check that proof doesn't work for leaf 2
+        assert!(!res);        
+        let (other_pk, _) = SchnorrJ::keygen(&sig_params, rng).unwrap();
         let res = GrothSetup::verify(
             &groth_vk,
-            &vec![leaves[2]],
+            &vec![other_pk.x, other_pk.y, ctx, tag, leaves[0], Fp::from(0u64), depth],
             &proof)
             .unwrap();
-        assert!(!res);        
-    }
-
-    #[test]    
-    fn root_circuit() {
-        let rng = &mut test_rng();
-        let params = setup_params(Curve::Bls381, POSEIDON_EXP, POSEIDON_WIDTH);
-        let hasher = Poseidon::<Fr> { params };
-        
-        // Expand constrained PRF to generate Merkle tree leaves 
-        let start = Instant::now();
-        let key = rand::thread_rng().gen::<[u8; 32]>();
-        let ggm = GGM::new();
-        let leaves_bytes = ggm.expand(&key, (HEIGHT0).try_into().unwrap());
-        let mut leaves = Vec::new();
-        for bytes in leaves_bytes {
-            leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
-        }
-        println!("CPRF expand: {:?}", start.elapsed());        
-        
-        // Construct Merkle tree and hash to root
-        let start = Instant::now();
-        let smt = SMT0::new_sequential(&leaves, &hasher, &DEFAULT_LEAF).unwrap();
-        let root = smt.root();
-        
-        println!("Compute Merkle root: {:?}", start.elapsed());
-        // Generate path for membership proof of leaf with label 0
-        let path = smt.generate_membership_proof(0);
-        
-        // Generate commitment to the root
-        let open = Fr::rand(rng);
-        let com = hasher.hash(&[root, open]).unwrap();
-        
-        // Generate a signature on com under pk
-        let sig_params = SchnorrJ::setup::<_>(rng).unwrap();
-        let (pk, sk) = SchnorrJ::keygen(&sig_params, rng).unwrap();
-        let sig = SchnorrJ::sign(&sig_params, &sk, &com, rng).unwrap();
-        assert!(SchnorrJ::verify(&sig_params, &pk, &com, &sig).unwrap());
-        
-        // Run trusted setup for circuit
-        let start = Instant::now();
-        let setup_circuit = C0::new(sig_params.clone(),
-                                    pk,
-                                    sig.clone(),
-                                    root,
-                                    com,
-                                    open,
-                                    leaves[0],
-                                    path.clone(),
-                                    hasher.clone()); 
-        let (groth_pk, groth_vk) = GrothSetup::circuit_specific_setup(
-            setup_circuit,
-            rng)
-            .unwrap();
-        println!("Trusted setup: {:?}", start.elapsed());
-        
-        // Generate proof for leaf 0
-        let start = Instant::now();
-        let circuit = C0::new(sig_params,
-                              pk,
-                              sig,
-                              root,
-                              com,
-                              open,
-                              leaves[0],
-                              path,
-                              hasher);
-        let proof = GrothSetup::prove(&groth_pk, circuit, rng).unwrap();
-        println!("Prover: {:?}", start.elapsed());        
-        
-        // Verify proof for leaf 0
-        let start = Instant::now();                        
+        assert!(!res);
+        let other_ctx = Context { publisher: 2, ..CTX }.to_field(&hasher);
         let res = GrothSetup::verify(
             &groth_vk,
-            &vec![leaves[0]],
+            &vec![pk.x, pk.y, other_ctx, tag, leaves[0], Fp::from(0u64), depth],
             &proof)
             .unwrap();
-        println!("Verifier: {:?}", start.elapsed());                
-        assert!(res);
+        assert!(!res);
     }
 
     #[test]
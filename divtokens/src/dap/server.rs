@@ -1,11 +1,11 @@
 //use aes::cipher::generic_array::GenericArray;    
 use ark_bls12_381::Fr;    
-use ark_crypto_primitives::SNARK;
-use ark_ff::{Fp256, PrimeField, UniformRand};
+use ark_ec::AffineCurve;
+use ark_ff::PrimeField;
+use ark_groth16::prepare_verifying_key;
 use ark_serialize::*;
-use ark_std::test_rng;
+use ark_std::rand::{CryptoRng, Rng};
 use arkworks_native_gadgets::poseidon::{
-    FieldHasher,
     Poseidon,
     PoseidonParameters,
     sbox::PoseidonSbox,
@@ -16,21 +16,38 @@ use arkworks_utils::{
     Curve,
     poseidon_params::setup_poseidon_params,
 };
-use bloomfilter::Bloom;
-use rand::Rng;
-use std::collections::HashSet;
+use parking_lot::RwLock;
+use rand_core::OsRng;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
 use crate::dap::{
+    batch::{self, Claim},
+    circuit,
+    dispatch,
+    error::DapError,
+    index,
     messages::{
         IssueRequest,
         IssueResponse,
         RedeemRequest,
         RedeemResponse,
     },
+    tree::subtree_root,
     types::*,    
 };
-use crate::schnorr::SignatureScheme;
+use crate::billing::{Earnings, Item, Ledger, Scheme};
 use crate::ggm::GGM;
+use crate::redemption::Rejection;
+use crate::schnorr::SignatureScheme;
+use crate::spent::{DEFAULT_SHARDS, ExactStore, Sharded, SpentStore};
 
 pub fn setup_params<F: PrimeField>(curve: Curve,
                                    exp: i8,
@@ -53,214 +70,675 @@ pub fn setup_params<F: PrimeField>(curve: Curve,
     pos
 }
 
-#[macro_export]
-macro_rules! mk_server {
-    ($smt: ident, $server: ident, $height: ident, $circ: ident) => {        
-        pub struct $server {
-            // TODO: Double-spend list
-            pub pp: PP,
-            pub sk: SigSecretKey,
-            pub groth_vks: Vec<GrothVerifyingKey>,
-            pub bloom: Bloom::<Fp>,
-            pub hset: HashSet::<Fp>,
+// Files written by `Server::save`: the parameters of the current epoch and
+// the verifying keys, whose circuits every epoch shares, and the issuer
+// secret key of each epoch under a directory named after it
+pub const PP_FILE: &str = "pp.bin";
+pub const VK_FILE: &str = "groth_vk.bin";
+pub const BUNDLE_VK_FILE: &str = "bundle_vk.bin";
+pub const SK_FILE: &str = "sk.bin";
+// Open epochs and when each started, written next to their directories
+pub const EPOCHS_FILE: &str = "epochs.bin";
+
+/// Issuer key of one epoch.
+pub struct EpochKeys {
+    pub pp: PP,
+    pub sk: SigSecretKey,
+    // Seconds past the Unix epoch
+    pub started: u64,
+}
+
+impl EpochKeys {
+    /// Generates a fresh issuer key for `epoch`, starting now, reusing the
+    /// circuits of `pp`.
+    pub fn generate<R: Rng + CryptoRng>(epoch: u64, pp: &PP, rng: &mut R) -> Self {
+        let (pk, sk) = SchnorrJ::keygen(&pp.sig_params, rng).unwrap();
+        let pp = PP { epoch, pk, ..pp.clone() };
+        Self { pp, sk, started: now() }
+    }
+
+    /// Writes the issuer secret key to `dir`, readable by its owner only.
+    /// The public key follows from it, and the rest of the parameters are
+    /// shared with every epoch.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), DapError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(dir.join(SK_FILE))?;
+        // The mode only applies to a new file
+        #[cfg(unix)]
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        self.sk.secret_key.serialize(file)?;
+        Ok(())
+    }
+
+    /// Reads the key of `epoch`, which started at `started`, written by
+    /// `save`, reusing the circuits of `pp`.
+    pub fn load<P: AsRef<Path>>(epoch: u64,
+                                started: u64,
+                                pp: &PP,
+                                dir: P)
+                                -> Result<Self, DapError> {
+        let sk_bytes = fs::read(dir.as_ref().join(SK_FILE))?;
+        let secret_key = FpEd::deserialize(&*sk_bytes)?;
+        let public_key: SigPublicKey = pp.sig_params.generator.mul(secret_key).into();
+        // The parameters carry the public key of their own epoch, which
+        // clients verify against
+        if epoch == pp.epoch && public_key != pp.pk {
+            return Err(SerializationError::InvalidData.into());
         }
+        let sk = SigSecretKey { secret_key, public_key };
+        let pp = PP { epoch, pk: public_key, ..pp.clone() };
 
-        impl $server {
-            pub fn new() -> Self {
-                let rng = &mut test_rng();
-                
-                // Generate public parameters
-                let sig_params = SchnorrJ::setup::<_>(rng).unwrap();
-                let (pk, sk) = SchnorrJ::keygen(&sig_params, rng).unwrap();                
-                let params = setup_params(Curve::Bls381,
-                                          POSEIDON_EXP,
-                                          POSEIDON_WIDTH);
-                let hasher = Poseidon::<Fr> { params };
-
-                let mut groth_pks = vec![];
-                let mut groth_vks = vec![];                
-                for lvl in 0..=$height {
-                    // Expand constrained PRF to generate Merkle tree leaves
-                    let key = rand::thread_rng().gen::<[u8; 32]>();
-                    let ggm = GGM::new();
-                    let leaves_bytes = ggm.expand(&key, lvl.try_into().unwrap());
-                    let mut leaves: Vec<Fp> = Vec::new();
-                    for bytes in leaves_bytes {
-                        leaves.push(Fp256::from_le_bytes_mod_order(&bytes));
-                    }
-                    
-                    macro_rules! setup {
-                        ($smt2:ident, $circ2:ident) => {
-                            {
-                                // Construct Merkle tree and hash to root
-                                let smt = $smt2::new_sequential(&leaves, &hasher, &DEFAULT_LEAF).unwrap();
-                                let root = smt.root();
-                                // Generate path for membership proof of leaf with label 0
-                                let path = smt.generate_membership_proof(0);
-                                
-                                // Generate commitment to the root
-
-                                let open = Fr::rand(rng);
-                                let com = hasher.hash(&[root, open]).unwrap();
-                                
-                                // Generate a signature on com under pk
-                                let sig = SchnorrJ::sign(&sig_params, &sk, &com, rng).unwrap();
-                                assert!(SchnorrJ::verify(&sig_params, &pk, &com, &sig).unwrap());
-                                
-                                // Run trusted setup for circuit
-                                let setup_circuit = $circ2::new(sig_params.clone(),
-                                                                pk,
-                                                                sig.clone(),
-                                                                root,
-                                                                com,
-                                                                open,
-                                                                leaves[0],
-                                                                path.clone(),
-                                                                hasher.clone()); 
-                                let (groth_pk, groth_vk) = GrothSetup::circuit_specific_setup(
-                                    setup_circuit,
-                                    &mut test_rng())
-                                    .unwrap();
-                                groth_pks.push(groth_pk);
-                                groth_vks.push(groth_vk);
-                            }
-                        }
-                    }
-                    
-                    match lvl {
-                        0  => setup![SMT0 , C0 ],
-                        1  => setup![SMT1 , C1 ],
-                        2  => setup![SMT2 , C2 ],
-                        3  => setup![SMT3 , C3 ],
-                        4  => setup![SMT4 , C4 ],
-                        5  => setup![SMT5 , C5 ],
-                        6  => setup![SMT6 , C6 ],
-                        7  => setup![SMT7 , C7 ],
-                        8  => setup![SMT8 , C8 ],
-                        9  => setup![SMT9 , C9 ],
-                        10 => setup![SMT10, C10],
-                        11 => setup![SMT11, C11],
-                        12 => setup![SMT12, C12],
-                        _ => panic!("Shouldn't reach this case!"),
-                    };
+        Ok(Self { pp, sk, started })
+    }
+}
+
+pub struct Server<S: SpentStore = ExactStore> {
+    pub params: Params,
+    // One circuit for every depth a coin's node can sit at. The issuer key
+    // is a public input, so every epoch shares it
+    pub groth_vk: GrothVerifyingKey,
+    pub groth_pvk: GrothPreparedVerifyingKey,
+    // The same circuit with `params.bundle` slots, if bundles are accepted
+    pub bundle_vk: Option<GrothVerifyingKey>,
+    pub bundle_pvk: Option<GrothPreparedVerifyingKey>,
+    // Keys of the open epochs; coins of any other epoch are turned down
+    keys: RwLock<BTreeMap<u64, Arc<EpochKeys>>>,
+    // Spent nodes, indexed by epoch and root-to-node path and sharded by
+    // the id of the wallet's root
+    pub spent: Sharded<S>,
+    pub limits: Limits,
+    // Whether redeem also expands the constrained key of each revealed coin
+    // and checks that it hashes to the proven node. The proof already binds
+    // the node to its path, so this is off unless asked for; the leaves it
+    // expands are bounded by `limits.max_leaves`
+    pub check_keys: bool,
+    pub policy: EpochPolicy,
+    // Advertiser accounts charged for each wallet issued, if billing is on
+    pub ledger: Option<Arc<Ledger>>,
+    // Publisher accounts credited for each accepted redemption, if kept
+    pub earnings: Option<Arc<Earnings>>,
+}
+
+impl Server {
+    pub fn new(params: Params) -> Self {
+        Self::with_store(params, EpochPolicy::default(), Sharded::with_shards(DEFAULT_SHARDS))
+    }
+}
+
+impl<S: SpentStore> Server<S> {
+    /// Starts a server in epoch 0, rotating keys under `policy` and
+    /// recording spends in `spent`.
+    pub fn with_store(params: Params, policy: EpochPolicy, spent: Sharded<S>) -> Self {
+        Self::with_rng(params, policy, spent, &mut OsRng)
+    }
+
+    /// Like `with_store`, but draws the issuer key and circuit setup
+    /// randomness from `rng`.
+    pub fn with_rng<R: Rng + CryptoRng>(params: Params,
+                                        policy: EpochPolicy,
+                                        spent: Sharded<S>,
+                                        rng: &mut R)
+                                        -> Self {
+        let height = params.height;
+        assert!(height <= MAX_HEIGHT, "Wallet height {} exceeds {}", height, MAX_HEIGHT);
+
+        // Generate public parameters
+        let sig_params = SchnorrJ::setup::<_>(rng).unwrap();
+        let (pk, sk) = SchnorrJ::keygen(&sig_params, rng).unwrap();
+        let poseidon_params = setup_params(Curve::Bls381,
+                                           POSEIDON_EXP,
+                                           POSEIDON_WIDTH);
+        let hasher = Poseidon::<Fr> { params: poseidon_params };
+
+        // One circuit for every depth a coin's node can sit at
+        let (groth_pk, groth_vk) = dispatch::setup(height, params.mode, 1, &sig_params, &hasher, rng);
+        let groth_pvk = prepare_verifying_key(&groth_vk);
+        let (bundle_pk, bundle_vk) = if params.bundle > 0 {
+            let (bundle_pk, bundle_vk) = dispatch::setup(height,
+                                                         params.mode,
+                                                         params.bundle,
+                                                         &sig_params,
+                                                         &hasher,
+                                                         rng);
+            (Some(Arc::new(bundle_pk)), Some(bundle_vk))
+        } else {
+            (None, None)
+        };
+        let bundle_pvk = bundle_vk.as_ref().map(prepare_verifying_key);
+
+        let epoch = 0;
+        let pp = PP {
+            epoch,
+            height,
+            mode: params.mode,
+            sig_params,
+            hasher,
+            pk,
+            groth_pk: Arc::new(groth_pk),
+            bundle: params.bundle,
+            bundle_pk,
+        };
+        let keys = RwLock::new(BTreeMap::from([(epoch, Arc::new(EpochKeys { pp, sk, started: now() }))]));
+
+        Self {
+            params,
+            groth_vk,
+            groth_pvk,
+            bundle_vk,
+            bundle_pvk,
+            keys,
+            spent,
+            limits: Limits::default(),
+            check_keys: false,
+            policy,
+            ledger: None,
+            earnings: None,
+        }
+    }
+
+    /// Writes the parameters, verifying keys and the issuer keys of every
+    /// open epoch to `dir`, so a restarted server can `load` them.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), DapError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let mut vk_bytes = vec![];
+        self.groth_vk.serialize(&mut vk_bytes)?;
+        fs::write(dir.join(VK_FILE), vk_bytes)?;
+        if let Some(bundle_vk) = &self.bundle_vk {
+            let mut vk_bytes = vec![];
+            bundle_vk.serialize(&mut vk_bytes)?;
+            fs::write(dir.join(BUNDLE_VK_FILE), vk_bytes)?;
+        }
+
+        // Every epoch shares the circuits, so their proving keys are written
+        // once, with the parameters of the current epoch
+        let keys = self.keys.read();
+        keys.values().next_back().unwrap().pp.save(dir.join(PP_FILE))?;
+        for (epoch, epoch_keys) in keys.iter() {
+            epoch_keys.save(dir.join(epoch.to_string()))?;
+        }
+
+        let epochs: Vec<(u64, u64)> = keys
+            .iter()
+            .map(|(epoch, epoch_keys)| (*epoch, epoch_keys.started))
+            .collect();
+        let mut epochs_bytes = vec![];
+        epochs.serialize(&mut epochs_bytes)?;
+        fs::write(dir.join(EPOCHS_FILE), epochs_bytes)?;
+        Ok(())
+    }
+
+    /// Restores a server from the files written by `save`, rotating keys
+    /// under `policy` and recording spends in `spent`.
+    pub fn load<P: AsRef<Path>>(dir: P,
+                                policy: EpochPolicy,
+                                spent: Sharded<S>)
+                                -> Result<Self, DapError> {
+        let dir = dir.as_ref();
+        let pp = PP::load(dir.join(PP_FILE))?;
+        let epochs_bytes = fs::read(dir.join(EPOCHS_FILE))?;
+        let epochs = Vec::<(u64, u64)>::deserialize(&*epochs_bytes)?;
+
+        // The parameters are those of the current epoch
+        if epochs.last().map(|(epoch, _)| *epoch) != Some(pp.epoch) {
+            return Err(SerializationError::InvalidData.into());
+        }
+        let mut keys: BTreeMap<u64, Arc<EpochKeys>> = BTreeMap::new();
+        for (epoch, started) in epochs {
+            let epoch_keys = EpochKeys::load(epoch, started, &pp, dir.join(epoch.to_string()))?;
+            keys.insert(epoch, Arc::new(epoch_keys));
+        }
+        let params = Params { height: pp.height, mode: pp.mode, bundle: pp.bundle };
+
+        let vk_bytes = fs::read(dir.join(VK_FILE))?;
+        let groth_vk = GrothVerifyingKey::deserialize(&*vk_bytes)?;
+        let groth_pvk = prepare_verifying_key(&groth_vk);
+        let bundle_vk = if params.bundle > 0 {
+            let vk_bytes = fs::read(dir.join(BUNDLE_VK_FILE))?;
+            Some(GrothVerifyingKey::deserialize(&*vk_bytes)?)
+        } else {
+            None
+        };
+        let bundle_pvk = bundle_vk.as_ref().map(prepare_verifying_key);
+
+        Ok(Self {
+            params,
+            groth_vk,
+            groth_pvk,
+            bundle_vk,
+            bundle_pvk,
+            keys: RwLock::new(keys),
+            spent,
+            limits: Limits::default(),
+            check_keys: false,
+            policy,
+            ledger: None,
+            earnings: None,
+        })
+    }
+
+    /// Returns the keys of the current epoch, which new wallets are issued
+    /// under.
+    pub fn current(&self) -> Arc<EpochKeys> {
+        self.keys.read().values().next_back().unwrap().clone()
+    }
+
+    /// Returns the keys of `epoch`, if it is open.
+    pub fn epoch(&self, epoch: u64) -> Option<Arc<EpochKeys>> {
+        self.keys.read().get(&epoch).cloned()
+    }
+
+    /// Returns the public parameters of the current epoch.
+    pub fn setup(&self) -> PP {
+        self.current().pp.clone()
+    }
+
+    /// Starts the next epoch if the current one is over under `policy`.
+    /// Returns the new epoch.
+    pub fn tick(&self) -> Result<Option<u64>, DapError> {
+        let current = self.current();
+        if !self.policy.is_over(current.started, now()) {
+            return Ok(None);
+        }
+        self.rotate_to(current.pp.epoch + 1, &mut OsRng).map(Some)
+    }
+
+    /// Starts the next epoch right away. Returns the new epoch.
+    pub fn rotate(&self) -> Result<u64, DapError> {
+        self.rotate_with_rng(&mut OsRng)
+    }
+
+    /// Like `rotate`, but draws the issuer key from `rng`.
+    pub fn rotate_with_rng<R: Rng + CryptoRng>(&self, rng: &mut R) -> Result<u64, DapError> {
+        let next = self.current().pp.epoch + 1;
+        self.rotate_to(next, rng)
+    }
+
+    fn rotate_to<R: Rng + CryptoRng>(&self, epoch: u64, rng: &mut R) -> Result<u64, DapError> {
+        let epoch_keys = Arc::new(EpochKeys::generate(epoch, &self.current().pp, rng));
+        let closed: Vec<u64> = {
+            let mut keys = self.keys.write();
+            // Keep the keys of a concurrent rotation to the same epoch, which
+            // may already have signed
+            keys.entry(epoch).or_insert(epoch_keys);
+            let excess = keys.len().saturating_sub(self.policy.open.max(1));
+            let closed: Vec<u64> = keys.keys().take(excess).copied().collect();
+            for e in closed.iter() {
+                keys.remove(e);
+            }
+            closed
+        };
+
+        // Coins of closed epochs are turned down, so their spends need not
+        // be remembered. Their keys are gone first, so a redeem holding a
+        // shard either sees the epoch closed or finishes before it is pruned
+        for e in closed {
+            self.spent.remove_prefix(&index::epoch_prefix(e))?;
+        }
+        Ok(epoch)
+    }
+
+    /// Signs the commitment of a wallet worth `req.value` leaves for
+    /// `advertiser`, charging its value to their account if the exchange
+    /// bills. The caller vouches for `advertiser`, which is never taken from
+    /// the request itself.
+    pub fn issue(&self, advertiser: u64, req: IssueRequest) -> Result<IssueResponse, DapError> {
+        self.issue_with_rng(advertiser, req, &mut OsRng)
+    }
+
+    /// Like `issue`, but draws the signing nonce from `rng`.
+    pub fn issue_with_rng<R: Rng + CryptoRng>(&self,
+                                              advertiser: u64,
+                                              req: IssueRequest,
+                                              rng: &mut R)
+                                              -> Result<IssueResponse, DapError> {
+        // The circuit only checks the leaves spent fall below the value, so
+        // it must be one the tree can hold
+        if req.value == 0 || req.value > 1u64 << self.params.height {
+            return Err(DapError::InvalidValue(req.value));
+        }
+        let com = Fp::deserialize(&*req.com)?;
+        let keys = self.current();
+        let msg = circuit::signed_message(&keys.pp.hasher, com, req.value)?;
+        let sig = SchnorrJ::sign(&keys.pp.sig_params, &keys.sk, &msg, rng)?;
+        let mut prover_response = vec![];
+        sig.prover_response.serialize(&mut prover_response)?;
+
+        if let Some(ledger) = &self.ledger {
+            ledger.charge(advertiser, Item::Wallet(req.value))?;
+        }
+
+        Ok(IssueResponse {
+            id: req.id,
+            epoch: keys.pp.epoch,
+            sig: SchnorrSig {
+                prover_response,
+                verifier_challenge: sig.verifier_challenge,
+            }
+        })
+    }
+
+    pub fn redeem(&self, req: RedeemRequest) -> Result<RedeemResponse, DapError> {
+        self.redeem_with_rng(req, &mut OsRng)
+    }
+
+    /// Like `redeem`, but draws the batch verification scalars from `rng`.
+    pub fn redeem_with_rng<R: Rng + CryptoRng>(&self,
+                                               req: RedeemRequest,
+                                               rng: &mut R)
+                                               -> Result<RedeemResponse, DapError> {
+        // Bound the work before doing any of it
+        let spends: Vec<u8> = req.coins
+            .iter()
+            .map(|coin| coin.denom)
+            .chain(req.bundles.iter().flat_map(|bundle| bundle.coins.iter().map(|(denom, _)| *denom)))
+            .collect();
+        if spends.len() > self.limits.max_coins {
+            return Err(DapError::RequestTooLarge);
+        }
+        // An empty bundle would have no coin to answer for it
+        if req.bundles.iter().any(|bundle| bundle.coins.is_empty()) {
+            return Err(DapError::BundleSize(0));
+        }
+        let total_leaves: u64 = spends
+            .iter()
+            .filter(|&&denom| denom as usize <= self.params.height)
+            .map(|&denom| 1u64 << denom)
+            .sum();
+        if total_leaves > self.limits.max_leaves {
+            return Err(DapError::RequestTooLarge);
+        }
+
+        // Nodes spent by this request; only recorded once every coin checks out
+        let keys = self.keys.read().clone();
+        let mut pending = ExactStore::new();
+        let mut checks = Vec::with_capacity(spends.len());
+        // Claims of the coins and bundles that decode, each with its epoch
+        // and the checks and root-to-node paths of the coins it covers. A
+        // bundle stands or falls as a whole
+        let mut claims = vec![];
+        let mut owners = vec![];
+        let mut bundle_claims = vec![];
+        let mut bundle_owners = vec![];
+        let decoded = req.coins
+            .iter()
+            .map(|coin| (coin.epoch, 1, self.decode(coin, req.publisher, &keys)))
+            .chain(req.bundles
+                   .iter()
+                   .map(|bundle| (bundle.epoch,
+                                  bundle.coins.len(),
+                                  self.decode_bundle(bundle, req.publisher, &keys))));
+        for (j, (epoch, num_coins, result)) in decoded.enumerate() {
+            let first = checks.len();
+            let (claim, paths) = match result {
+                Ok(decoded) => decoded,
+                Err(reason) => {
+                    checks.extend(vec![Err(reason); num_coins]);
+                    continue;
+                }
+            };
+
+            // Check double-spend against overlapping coins in this request.
+            // The proof binds each node to its path, so the key's subtree
+            // need not be expanded. The nodes of a claim are only marked once
+            // all of them pass, so a rejected bundle leaves nothing behind
+            let mut claimed = ExactStore::new();
+            let mut overlap = false;
+            for path in paths.iter() {
+                if index::overlaps_path(&pending, epoch, path)
+                    || index::overlaps_path(&claimed, epoch, path) {
+                    overlap = true;
+                    break;
                 }
-                
-                let pp = PP { sig_params, hasher, pk, groth_pks };
-                
-                // For 100M items, 1/1000000 FP rate                
-                let bloom: Bloom<Fp> = Bloom::new_for_fp_rate(100000000, 0.000001);
-                let hset: HashSet<Fp> = HashSet::new();
-                
-                Self { pp, sk, groth_vks, bloom, hset }
+                index::mark_path(&mut claimed, epoch, path)?;
             }
+            if overlap {
+                checks.extend(vec![Err(Rejection::DoubleSpent); num_coins]);
+                continue;
+            }
+            for path in paths.iter() {
+                index::mark_path(&mut pending, epoch, path)?;
+            }
+
+            checks.extend(spends[first..first + num_coins].iter().map(|&denom| Ok(1u64 << denom)));
+            let covered: Vec<(usize, Vec<Vec<u8>>)> = (first..).zip(paths).collect();
+            if j < req.coins.len() {
+                claims.push(claim);
+                owners.push((epoch, covered));
+            } else {
+                bundle_claims.push(claim);
+                bundle_owners.push((epoch, covered));
+            }
+        }
 
-            pub fn setup(&self) -> PP {
-                self.pp.clone()
+        // Check proofs in one batch per circuit, before taking any lock. The
+        // issuer key is a public input, so coins of every epoch share it
+        let mut bad = batch::verify(&self.groth_pvk, &claims, rng)?
+            .into_iter()
+            .map(|j| &owners[j])
+            .collect::<Vec<_>>();
+        if let Some(bundle_pvk) = &self.bundle_pvk {
+            bad.extend(batch::verify(bundle_pvk, &bundle_claims, rng)?
+                       .into_iter()
+                       .map(|j| &bundle_owners[j]));
+        }
+        for (_, covered) in bad {
+            for (i, _) in covered {
+                checks[*i] = Err(Rejection::BadProof);
             }
+        }
 
-            pub fn issue(&mut self, req: IssueRequest) -> IssueResponse {
-                let rng = &mut test_rng();
-                let com = Fp::deserialize(&*req.com).unwrap();
-                let sig = SchnorrJ::sign(&self.pp.sig_params, &self.sk, &com, rng).unwrap();
-                //assert!(SchnorrJ::verify(&self.pp.sig_params, &self.pp.pk, &com, &sig).unwrap());        
-                let mut prover_response = vec![];
-                sig.prover_response.serialize(&mut prover_response).unwrap();
-                
-                IssueResponse {
-                    sig: SchnorrSig {
-                        prover_response,
-                        verifier_challenge: sig.verifier_challenge,
+        // Check double-spend against earlier requests and record the spends,
+        // holding every wallet's shard so no concurrent request interleaves.
+        // Shards are picked by the id of the wallet's root
+        owners.extend(bundle_owners);
+        let roots: Vec<&[u8]> = owners
+            .iter()
+            .flat_map(|(_, covered)| covered.iter().map(|(_, path)| &path[0][..]))
+            .collect();
+        let mut guards = self.spent.lock(&roots);
+        // An epoch closed since the keys were read may have had its spends
+        // pruned already, so its coins can no longer be checked
+        {
+            let open = self.keys.read();
+            for (epoch, covered) in owners.iter() {
+                if !open.contains_key(epoch) {
+                    for (i, _) in covered {
+                        checks[*i] = Err(Rejection::WrongEpoch);
                     }
                 }
             }
+        }
+        for (epoch, covered) in owners.iter() {
+            if covered.iter().any(|(i, path)| {
+                checks[*i].is_ok() && index::overlaps_path(guards.get(&path[0]), *epoch, path)
+            }) {
+                for (i, _) in covered {
+                    checks[*i] = Err(Rejection::DoubleSpent);
+                }
+            }
+        }
 
-            pub fn redeem(&mut self, req: RedeemRequest) -> RedeemResponse {
-                const L: u8 = 12;
-                for i in 0..req.coins.len() {
-                    let instance = Fp::deserialize(&*req.coins[i].instance_bytes).unwrap();            
-                    let proof = GrothProof::deserialize(&*req.coins[i].proof_bytes).unwrap();
-                    let key = req.coins[i].key;
-                    let denom = req.coins[i].denom;
-                    
-                    let ggm = GGM::new();                    
-                    let leaves_bytes = ggm.expand(&key, denom);
-                    let mut leaves: Vec<Fp> = vec![];
-                    for (j, bytes) in leaves_bytes.iter().enumerate() {
-                        let leaf = Fp256::from_le_bytes_mod_order(bytes);
-                        if j == 0 && leaf != instance {
-                            return RedeemResponse { valid: false};
-                        }
-                        // Check double-spend
-                        //if self.bloom.check_and_set(&leaf) {
-                        //    return RedeemResponse { valid: false };
-                        //};
-                        if self.hset.contains(&leaf) {
-                            return RedeemResponse { valid: false };                            
-                        }
-                        self.hset.insert(leaf);
-                        leaves.push(leaf);
-                    }
+        let response = RedeemResponse::settle(checks);
+        if response.valid {
+            // Every coin is valid, so record the spends, all of them or none
+            let marks: Vec<(&[u8], Vec<u8>)> = owners
+                .iter()
+                .flat_map(|(epoch, covered)| covered.iter().flat_map(move |(_, path)| {
+                    index::marks(*epoch, path).into_iter().map(move |mark| (&path[0][..], mark))
+                }))
+                .collect();
+            if guards.insert_all(&marks).is_err() {
+                return Ok(RedeemResponse::reject(spends.len(), Rejection::Aborted));
+            }
+            if let Some(earnings) = &self.earnings {
+                earnings.credit(req.publisher, Scheme::Dap, &response);
+            }
+        }
+        Ok(response)
+    }
+
+    // Decodes the proof claim a coin makes and the ids of its node's
+    // root-to-node path, or says why `publisher` can't redeem it
+    fn decode(&self,
+              coin: &Coin,
+              publisher: u64,
+              keys: &BTreeMap<u64, Arc<EpochKeys>>)
+              -> Result<(Claim, Vec<Vec<Vec<u8>>>), Rejection> {
+        let pp = open_pp(coin.epoch, &coin.context, publisher, keys)?;
+        let (node, path) = self.decode_node(pp, coin.denom, &coin.spend)?;
+        let proof = decode_proof(&coin.proof_bytes)?;
+        let ctx = coin.context.to_field(&pp.hasher);
+
+        let inputs = match node {
+            Node::Revealed { tag, node, depth, pos } => {
+                dispatch::inputs(&pp.pk, node, tag, depth, pos, ctx)
+            }
+            Node::Nullified { depth, nullifiers } => {
+                dispatch::nullified_inputs(&pp.pk, pp.height, depth, &nullifiers, ctx)
+            }
+        };
+        Ok((Claim { inputs, proof }, vec![path]))
+    }
+
+    // Like `decode`, for the coins of a bundle under its one proof
+    fn decode_bundle(&self,
+                     bundle: &Bundle,
+                     publisher: u64,
+                     keys: &BTreeMap<u64, Arc<EpochKeys>>)
+                     -> Result<(Claim, Vec<Vec<Vec<u8>>>), Rejection> {
+        let pp = open_pp(bundle.epoch, &bundle.context, publisher, keys)?;
+        if self.bundle_pvk.is_none() || bundle.coins.len() > pp.bundle {
+            return Err(Rejection::Malformed);
+        }
+        let mut tags = vec![];
+        let mut revealed = vec![];
+        let mut nullified = vec![];
+        let mut paths = vec![];
+        for (denom, spend) in bundle.coins.iter() {
+            let (node, path) = self.decode_node(pp, *denom, spend)?;
+            match node {
+                Node::Revealed { tag, node, depth, pos } => {
+                    tags.push(tag);
+                    revealed.push((node, depth, pos));
+                }
+                Node::Nullified { depth, nullifiers } => nullified.push((depth, nullifiers)),
+            }
+            paths.push(path);
+        }
+        let proof = decode_proof(&bundle.proof_bytes)?;
+        let ctx = bundle.context.to_field(&pp.hasher);
+
+        let inputs = match self.params.mode {
+            Mode::Revealed => {
+                // The proof covers a single wallet
+                if tags.iter().any(|tag| *tag != tags[0]) {
+                    return Err(Rejection::Malformed);
+                }
+                dispatch::bundle_inputs(&pp.pk, tags[0], &revealed, ctx, pp.bundle)
+            }
+            Mode::Nullified => {
+                dispatch::nullified_bundle_inputs(&pp.pk, pp.height, &nullified, ctx, pp.bundle)
+            }
+        };
+        Ok((Claim { inputs, proof }, paths))
+    }
+
+    // Decodes the node a coin of denomination `denom` spends and the ids of
+    // its root-to-node path
+    fn decode_node(&self,
+                   pp: &PP,
+                   denom: u8,
+                   spend: &Spend)
+                   -> Result<(Node, Vec<Vec<u8>>), Rejection> {
+        // A coin of denomination d is a node at depth height - d
+        let depth = self.params.height
+            .checked_sub(denom as usize)
+            .ok_or(Rejection::UnknownDenomination)?;
+        let instance_size = Fp::default().serialized_size();
 
-                    //// Check hash to instance
-                    //macro_rules! cases { 
-                    //    ($smt2: ident) => {
-                    //        {
-                    //            let smt = $smt2::new_sequential(&leaves,
-                    //                                            &self.pp.hasher,
-                    //                                            &DEFAULT_LEAF).unwrap();
-                    //            let root = smt.root();
-                    //            if root != instance {
-                    //                return RedeemResponse { valid: false };                        
-                    //            }
-                    //        }
-                    //    }
-                    //}
-                    //
-                    //match denom {
-                    //    0  => cases![SMT0 ],
-                    //    1  => cases![SMT1 ],
-                    //    2  => cases![SMT2 ],
-                    //    3  => cases![SMT3 ],
-                    //    4  => cases![SMT4 ],
-                    //    5  => cases![SMT5 ],
-                    //    6  => cases![SMT6 ],
-                    //    7  => cases![SMT7 ],
-                    //    8  => cases![SMT8 ],
-                    //    9  => cases![SMT9 ],
-                    //    10 => cases![SMT10],
-                    //    11 => cases![SMT11],
-                    //    12 => cases![SMT12],
-                    //    _ => panic!("Shouldn't reach this case"),
-                    //}
-                    
-                    // Check proof
-                    let res = GrothSetup::verify(
-                        //&self.groth_vks[(L - req.coins[i].denom) as usize],
-                        &self.groth_vks[HEIGHT12],
-                        &vec![instance],
-                        &proof)
-                        .unwrap();
-                    if !res {
-                        return RedeemResponse { valid: false };
+        match (spend, self.params.mode) {
+            (Spend::Revealed { key, tag_bytes, pos, instance_bytes }, Mode::Revealed) => {
+                if instance_bytes.len() != instance_size
+                    || tag_bytes.len() != instance_size
+                    || pos >> depth != 0 {
+                    return Err(Rejection::Malformed);
+                }
+                let node = Fp::deserialize(&**instance_bytes).map_err(|_| Rejection::Malformed)?;
+                let tag = Fp::deserialize(&**tag_bytes).map_err(|_| Rejection::Malformed)?;
+                if self.check_keys {
+                    // Check the key's subtree hashes to the node
+                    let leaves: Vec<Fp> = GGM::new()
+                        .expand(key, denom)
+                        .iter()
+                        .map(|bytes| Fp::from_le_bytes_mod_order(bytes))
+                        .collect();
+                    match subtree_root(&pp.hasher, &leaves) {
+                        Ok(root) if root == node => (),
+                        _ => return Err(Rejection::BadProof),
                     }
                 }
-                RedeemResponse { valid: true }
-            }    
+                let path = index::path(tag_bytes, depth, *pos);
+                Ok((Node::Revealed { tag, node, depth, pos: *pos }, path))
+            }
+            (Spend::Nullified { nullifiers: nf_bytes }, Mode::Nullified) => {
+                // One nullifier per level from the root down to the node
+                if nf_bytes.len() != depth + 1
+                    || nf_bytes.iter().any(|nf| nf.len() != instance_size) {
+                    return Err(Rejection::Malformed);
+                }
+                let nullifiers = nf_bytes
+                    .iter()
+                    .map(|nf| Fp::deserialize(&**nf))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| Rejection::Malformed)?;
+                Ok((Node::Nullified { depth, nullifiers }, nf_bytes.clone()))
+            }
+            // A coin for the other mode
+            _ => Err(Rejection::Malformed),
         }
     }
 }
 
-mk_server![SMT, Server, HEIGHT, SpendC];
-mk_server![SMT0 , Server0 , HEIGHT0 , C0 ];
-mk_server![SMT1 , Server1 , HEIGHT1 , C1 ];
-mk_server![SMT2 , Server2 , HEIGHT2 , C2 ];
-mk_server![SMT3 , Server3 , HEIGHT3 , C3 ];
-mk_server![SMT4 , Server4 , HEIGHT4 , C4 ];
-mk_server![SMT5 , Server5 , HEIGHT5 , C5 ];
-mk_server![SMT6 , Server6 , HEIGHT6 , C6 ];
-mk_server![SMT7 , Server7 , HEIGHT7 , C7 ];
-mk_server![SMT8 , Server8 , HEIGHT8 , C8 ];
-mk_server![SMT9 , Server9 , HEIGHT9 , C9 ];
-mk_server![SMT10, Server10, HEIGHT10, C10];
-mk_server![SMT11, Server11, HEIGHT11, C11];
-mk_server![SMT12, Server12, HEIGHT12, C12];
+// A coin's node, as it enters the public inputs of a proof
+enum Node {
+    Revealed { tag: Fp, node: Fp, depth: usize, pos: u64 },
+    Nullified { depth: usize, nullifiers: Vec<Fp> },
+}
+
+// Returns the parameters of the open epoch a coin or bundle for `context`
+// was issued in, or says why `publisher` can't redeem it
+fn open_pp<'a>(epoch: u64,
+               context: &Context,
+               publisher: u64,
+               keys: &'a BTreeMap<u64, Arc<EpochKeys>>)
+               -> Result<&'a PP, Rejection> {
+    // The proof fails under any other context, so this only names the
+    // reason
+    if context.publisher != publisher {
+        return Err(Rejection::WrongPublisher);
+    }
+    keys.get(&epoch)
+        .map(|epoch_keys| &epoch_keys.pp)
+        .ok_or(Rejection::WrongEpoch)
+}
+
+// Seconds past the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn decode_proof(proof_bytes: &[u8]) -> Result<GrothProof, Rejection> {
+    if proof_bytes.len() != GrothProof::default().serialized_size() {
+        return Err(Rejection::Malformed);
+    }
+    GrothProof::deserialize(proof_bytes).map_err(|_| Rejection::Malformed)
+}
@@ -0,0 +1,88 @@
+use ark_bls12_381::Fr;
+use ark_crypto_primitives::Error;
+use arkworks_native_gadgets::{
+    merkle_tree::MerkleError,
+    poseidon::FieldHasher,
+};
+
+use crate::dap::types::Fp;
+
+/// A complete binary Merkle tree whose height is only known at runtime.
+///
+/// Nodes use the same indexing as `SparseMerkleTree::tree`: the root is at
+/// index 0 and node `i` has children `2i + 1` and `2i + 2`, so the node at
+/// `depth` with position `pos` in its level is at `node_index(depth, pos)`.
+#[derive(Clone)]
+pub struct MerkleTree {
+    pub height: usize,
+    pub nodes: Vec<Fp>,
+}
+
+impl MerkleTree {
+    /// Builds the tree over `leaves`, whose number must be a power of two.
+    pub fn new<H: FieldHasher<Fr>>(hasher: &H,
+                                   leaves: &[Fp])
+                                   -> Result<Self, Error> {
+        if leaves.is_empty() || !leaves.len().is_power_of_two() {
+            return Err(MerkleError::InvalidLeaf.into());
+        }
+        let height = leaves.len().trailing_zeros() as usize;
+        let first_leaf = leaves.len() - 1;
+
+        let mut nodes = vec![Fp::default(); 2 * leaves.len() - 1];
+        nodes[first_leaf..].copy_from_slice(leaves);
+        for i in (0..first_leaf).rev() {
+            nodes[i] = hasher.hash_two(&nodes[2 * i + 1], &nodes[2 * i + 2])?;
+        }
+
+        Ok(Self { height, nodes })
+    }
+
+    /// Returns the Merkle tree root.
+    pub fn root(&self) -> Fp {
+        self.nodes[0]
+    }
+
+    /// Returns the node at `depth` with position `pos` in its level.
+    pub fn node(&self, depth: usize, pos: u64) -> Fp {
+        self.nodes[node_index(depth, pos) as usize]
+    }
+
+    /// Gives the `(left, right)` pairs on the path from the node at `depth`
+    /// with position `pos` up to the root, lowest level first.
+    pub fn path(&self, depth: usize, pos: u64) -> Vec<(Fp, Fp)> {
+        let mut path = Vec::with_capacity(depth);
+        let mut index = node_index(depth, pos) as usize;
+        while index != 0 {
+            let (left, right) = if index % 2 == 1 {
+                (index, index + 1)
+            } else {
+                (index - 1, index)
+            };
+            path.push((self.nodes[left], self.nodes[right]));
+            index = (index - 1) / 2;
+        }
+        path
+    }
+}
+
+/// Returns the index in `SparseMerkleTree::tree` of the node at `depth` with
+/// position `pos` in its level.
+pub fn node_index(depth: usize, pos: u64) -> u64 {
+    (1u64 << depth) - 1 + pos
+}
+
+/// Returns the depth and position in its level of the node at `index` in
+/// `SparseMerkleTree::tree`; the inverse of `node_index`.
+pub fn node_at(index: u64) -> (usize, u64) {
+    let depth = (63 - (index + 1).leading_zeros()) as usize;
+    (depth, index + 1 - (1u64 << depth))
+}
+
+/// Hashes `leaves` pairwise up to a single root. For `2^d` leaves this is the
+/// root of a height-`d` `SparseMerkleTree` over them.
+pub fn subtree_root<H: FieldHasher<Fr>>(hasher: &H,
+                                        leaves: &[Fp])
+                                        -> Result<Fp, Error> {
+    Ok(MerkleTree::new(hasher, leaves)?.root())
+}
@@ -19,19 +19,30 @@ use ark_ed_on_bls12_381::{
 use ark_ff::Fp256;
 use ark_groth16::{
     Groth16,
+    PreparedVerifyingKey,
     Proof,
     ProvingKey,
     VerifyingKey,
 };
-//use ark_serialize::*;
+use ark_serialize::*;
 use arkworks_native_gadgets::{
     merkle_tree::SparseMerkleTree,
-    poseidon::Poseidon,
+    poseidon::{FieldHasher, Poseidon},
 };
 use arkworks_r1cs_gadgets::poseidon::PoseidonGadget;
+use arkworks_utils::Curve;
 use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+};
 
-use crate::dap::circuit::{RootCircuit, SpendCircuit};
+use crate::dap::{
+    circuit::SpendCircuit,
+    error::DapError,
+    server::setup_params,
+};
 use crate::schnorr::{
     Parameters as SchnorrParameters,
     Schnorr,
@@ -58,6 +69,7 @@ pub type FpEd = Fp256<ark_ed_on_bls12_381::FrParameters>;
 pub type GrothSetup = Groth16<Bls12_381>;
 pub type GrothProvingKey = ProvingKey<Bls12<Bls12Parameters>>;
 pub type GrothVerifyingKey = VerifyingKey<Bls12<Bls12Parameters>>;
+pub type GrothPreparedVerifyingKey = PreparedVerifyingKey<Bls12_381>;
 pub type GrothProof = Proof<Bls12_381>;
 
 // Circuit
@@ -67,65 +79,233 @@ pub const POSEIDON_EXP: i8 = 3;
 pub const POSEIDON_WIDTH: u8 = 3;
 pub type SchnorrJ = Schnorr<JubJub>;
 
-pub const HEIGHT: usize = 10;
-pub type SMT = SparseMerkleTree<Fr, Poseidon<Fr>, HEIGHT>;
-pub type SpendC = SpendCircuit<Fr,
-                               JubJub,
-                               SchnorrJ,
-                               SchnorrSignatureVerifyGadget<JubJub, JubJubVar>,
-                               PoseidonGadget<Fr>,
-                               HEIGHT>;
-
-pub const HEIGHT0: usize = 0;
-pub type SMT0 = SparseMerkleTree<Fr, Poseidon<Fr>, HEIGHT0>;
-pub type C0 = RootCircuit<Fr,
-                          JubJub,
-                          SchnorrJ,
-                          SchnorrSignatureVerifyGadget<JubJub, JubJubVar>,
-                          PoseidonGadget<Fr>,
-                          HEIGHT0>;
-
-#[macro_export]
-macro_rules! circ_types {
-    ($height: ident, $n: literal, $smt:ident, $circ:ident) => {
-        pub const $height: usize = $n;
-        pub type $smt = SparseMerkleTree<Fr, Poseidon<Fr>, $height>;
-        pub type $circ = SpendCircuit<Fr,
-                                      JubJub,
-                                      SchnorrJ,
-                                      SchnorrSignatureVerifyGadget<JubJub, JubJubVar>,
-                                      PoseidonGadget<Fr>,
-                                      $height>;
-    };
-}
-
-circ_types![HEIGHT1 , 1 , SMT1 , C1 ];
-circ_types![HEIGHT2 , 2 , SMT2 , C2 ];
-circ_types![HEIGHT3 , 3 , SMT3 , C3 ];
-circ_types![HEIGHT4 , 4 , SMT4 , C4 ];
-circ_types![HEIGHT5 , 5 , SMT5 , C5 ];
-circ_types![HEIGHT6 , 6 , SMT6 , C6 ];
-circ_types![HEIGHT7 , 7 , SMT7 , C7 ];
-circ_types![HEIGHT8 , 8 , SMT8 , C8 ];
-circ_types![HEIGHT9 , 9 , SMT9 , C9 ];
-circ_types![HEIGHT10, 10, SMT10, C10];
-circ_types![HEIGHT11, 11, SMT11, C11];
-circ_types![HEIGHT12, 12, SMT12, C12];
+/// Largest wallet height with a circuit instantiated for it.
+pub const MAX_HEIGHT: usize = 12;
+
+pub type SMT<const N: usize> = SparseMerkleTree<Fr, Poseidon<Fr>, N>;
+pub type SpendC<const N: usize> = SpendCircuit<Fr,
+                                               JubJub,
+                                               SchnorrJ,
+                                               SchnorrSignatureVerifyGadget<JubJub, JubJubVar>,
+                                               PoseidonGadget<Fr>,
+                                               N>;
+
+/// Deployment parameters chosen by the exchange.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Params {
+    /// Height of each wallet's tree; a wallet is worth 2^height
+    pub height: usize,
+    /// What a coin reveals of the node it spends
+    pub mode: Mode,
+    /// Most nodes of one wallet a bundle spends under a single proof, or 0
+    /// to only accept coins
+    pub bundle: usize,
+}
+
+/// What a coin reveals of the node it spends.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Mode {
+    /// The node, its constrained key, wallet tag and position
+    Revealed,
+    /// Only the nullifiers of the node and its ancestors
+    Nullified,
+}
+
+/// Caps on the work a single redeem request can make the exchange do.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Limits {
+    /// Most coins in one request
+    pub max_coins: usize,
+    /// Most leaves the coins in one request may be worth together
+    pub max_leaves: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        // Enough for any u16 payment
+        Self { max_coins: 64, max_leaves: 1 << 16 }
+    }
+}
+
+/// When the exchange rotates its issuer key, and for how long coins issued
+/// under a key stay redeemable.
+///
+/// Epochs are numbered in the order they start, from 0, and each is over
+/// `length` seconds after it started, unless rotated early.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct EpochPolicy {
+    /// Seconds between key rotations
+    pub length: u64,
+    /// Most recent epochs whose coins are still redeemed, at least 1. Spends
+    /// in older epochs are forgotten
+    pub open: usize,
+}
+
+impl EpochPolicy {
+    /// Returns whether an epoch that started at `started` is over at `time`,
+    /// both in seconds past the Unix epoch.
+    pub fn is_over(&self, started: u64, time: u64) -> bool {
+        time >= started.saturating_add(self.length.max(1))
+    }
+}
+
+impl Default for EpochPolicy {
+    fn default() -> Self {
+        // Daily keys, each redeemable for a day after the next one starts
+        Self { length: 24 * 60 * 60, open: 2 }
+    }
+}
+
+/// What a coin is spent on. It is bound into the coin's proof, so only the
+/// publisher the coin was made out to can redeem it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Context {
+    pub publisher: u64,
+    pub auction: u64,
+}
+
+impl Context {
+    /// Hashes the context to the public input of a spend proof.
+    pub fn to_field(&self, hasher: &Poseidon<Fr>) -> Fp {
+        hasher.hash(&[Fp::from(self.publisher), Fp::from(self.auction)]).unwrap()
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Coin {
+    // Epoch of the key the wallet was issued under
+    pub epoch: u64,
+    pub context: Context,
     pub denom: u8,
-    pub key: [u8; 32],
-    pub instance_bytes: Vec<u8>,
+    pub spend: Spend,
     pub proof_bytes: Vec<u8>,
 }
 
-// Public parameters
-// TODO: Serialize
+/// The node a coin spends, as revealed under the exchange's `Mode`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Spend {
+    Revealed {
+        // Constrained key for the subtree under the node
+        key: [u8; 32],
+        // Wallet tag H(root) and the node's position in its level
+        tag_bytes: Vec<u8>,
+        pos: u64,
+        instance_bytes: Vec<u8>,
+    },
+    Nullified {
+        // Nullifiers of the node's ancestors and the node, root first
+        nullifiers: Vec<Vec<u8>>,
+    },
+}
+
+/// Several nodes of one wallet, spent under a single proof.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Bundle {
+    // Epoch of the key the wallet was issued under
+    pub epoch: u64,
+    pub context: Context,
+    // Denomination and node of each coin in the bundle
+    pub coins: Vec<(u8, Spend)>,
+    pub proof_bytes: Vec<u8>,
+}
+
+// Public parameters of one epoch
 #[derive(Clone)]
 pub struct PP {
+    pub epoch: u64,
+    pub height: usize,
+    pub mode: Mode,
     pub sig_params: SigParams,
     pub hasher: Poseidon::<Fr>,    
     pub pk: SigPublicKey,
-    pub groth_pks: Vec<GrothProvingKey>,    
+    // Shared by every epoch, since the issuer key is a public input
+    pub groth_pk: Arc<GrothProvingKey>,
+    // Slots of the bundle circuit and its proving key, if bundles are
+    // accepted
+    pub bundle: usize,
+    pub bundle_pk: Option<Arc<GrothProvingKey>>,
+}
+
+impl PP {
+    /// Writes the parameters to the file at `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), DapError> {
+        let mut bytes = vec![];
+        self.serialize(&mut bytes)?;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Reads parameters written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, DapError> {
+        let bytes = fs::read(path)?;
+        Ok(Self::deserialize(&*bytes)?)
+    }
+}
+
+// The Poseidon parameters are fixed by POSEIDON_EXP and POSEIDON_WIDTH, so the
+// hasher is rebuilt on load rather than stored
+impl CanonicalSerialize for PP {
+    fn serialize<W: Write>(&self, mut writer: W) -> Result<(), SerializationError> {
+        self.epoch.serialize(&mut writer)?;
+        self.height.serialize(&mut writer)?;
+        (self.mode == Mode::Nullified).serialize(&mut writer)?;
+        self.sig_params.generator.serialize(&mut writer)?;
+        self.pk.serialize(&mut writer)?;
+        self.groth_pk.serialize(&mut writer)?;
+        self.bundle.serialize(&mut writer)?;
+        match &self.bundle_pk {
+            Some(bundle_pk) => bundle_pk.serialize(&mut writer),
+            None => Ok(()),
+        }
+    }
+
+    fn serialized_size(&self) -> usize {
+        self.epoch.serialized_size()
+            + self.height.serialized_size()
+            + true.serialized_size()
+            + self.sig_params.generator.serialized_size()
+            + self.pk.serialized_size()
+            + self.groth_pk.serialized_size()
+            + self.bundle.serialized_size()
+            + self.bundle_pk.as_ref().map_or(0, |bundle_pk| bundle_pk.serialized_size())
+    }
+}
+
+impl CanonicalDeserialize for PP {
+    fn deserialize<R: Read>(mut reader: R) -> Result<Self, SerializationError> {
+        let epoch = u64::deserialize(&mut reader)?;
+        let height = usize::deserialize(&mut reader)?;
+        let mode = if bool::deserialize(&mut reader)? {
+            Mode::Nullified
+        } else {
+            Mode::Revealed
+        };
+        let generator = SigPublicKey::deserialize(&mut reader)?;
+        let pk = SigPublicKey::deserialize(&mut reader)?;
+        let groth_pk = GrothProvingKey::deserialize(&mut reader)?;
+        // The bundle circuit's key is only written if it has slots
+        let bundle = usize::deserialize(&mut reader)?;
+        let bundle_pk = if bundle > 0 {
+            Some(Arc::new(GrothProvingKey::deserialize(&mut reader)?))
+        } else {
+            None
+        };
+        if height > MAX_HEIGHT {
+            return Err(SerializationError::InvalidData);
+        }
+        let poseidon_params = setup_params(Curve::Bls381,
+                                           POSEIDON_EXP,
+                                           POSEIDON_WIDTH);
+        Ok(Self {
+            epoch,
+            height,
+            mode,
+            sig_params: SigParams { generator },
+            hasher: Poseidon::<Fr> { params: poseidon_params },
+            pk,
+            groth_pk: Arc::new(groth_pk),
+            bundle,
+            bundle_pk,
+        })
+    }
 }
@@ -1,7 +1,10 @@
+pub mod billing;
 pub mod ggm;
 pub mod schnorr;
 pub mod dap;
 //pub mod merkle_tree;
 //pub mod merkle_tree_r1cs;
 
+pub mod redemption;
 pub mod sap;
+pub mod spent;
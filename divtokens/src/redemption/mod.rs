@@ -0,0 +1,76 @@
+//! Per-coin redemption results shared by the DAP and SAP exchanges.
+//!
+//! A redeem request is settled all-or-nothing: its coins are only credited
+//! if every one of them checks out. Either way, the response says for each
+//! coin what it was worth or why it was turned down, so a publisher can
+//! reconcile its earnings, resubmit the good coins of a rejected request and
+//! dispute the rest.
+use serde_derive::{Deserialize, Serialize};
+
+/// Why the exchange turned down a coin.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Rejection {
+    /// The coin, or one overlapping it, has already been redeemed.
+    DoubleSpent,
+    /// The coin's proof or token does not verify.
+    BadProof,
+    /// A field of the coin cannot be decoded.
+    Malformed,
+    /// The coin's denomination is not one the exchange issues.
+    UnknownDenomination,
+    /// The coin was issued under a key that is not accepted.
+    WrongEpoch,
+    /// The coin was made out to another publisher.
+    WrongPublisher,
+    /// The coin checked out, but another coin in the request did not, or
+    /// the exchange could not record the request. Nothing in the request was
+    /// recorded, so its good coins can be resubmitted.
+    Aborted,
+}
+
+/// The outcome of redeeming one coin.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CoinResult {
+    /// Credited with this value.
+    Accepted(u64),
+    Rejected(Rejection),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RedeemResponse {
+    /// Whether the request was accepted, and its coins credited
+    pub valid: bool,
+    /// Total value credited
+    pub value: u64,
+    /// One result per coin, in request order
+    pub coins: Vec<CoinResult>,
+}
+
+impl RedeemResponse {
+    /// Settles a request from the checks of its coins, each giving the
+    /// coin's value or why it was turned down.
+    pub fn settle(checks: Vec<Result<u64, Rejection>>) -> Self {
+        let valid = checks.iter().all(Result::is_ok);
+        let coins: Vec<CoinResult> = checks
+            .into_iter()
+            .map(|check| match check {
+                Ok(value) if valid => CoinResult::Accepted(value),
+                Ok(_) => CoinResult::Rejected(Rejection::Aborted),
+                Err(reason) => CoinResult::Rejected(reason),
+            })
+            .collect();
+        let value = coins
+            .iter()
+            .map(|coin| match coin {
+                CoinResult::Accepted(value) => *value,
+                CoinResult::Rejected(_) => 0,
+            })
+            .sum();
+        Self { valid, value, coins }
+    }
+
+    /// Rejects every coin of a request for `reason`.
+    pub fn reject(num_coins: usize, reason: Rejection) -> Self {
+        Self::settle(vec![Err(reason); num_coins])
+    }
+}
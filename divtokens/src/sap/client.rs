@@ -11,7 +11,7 @@ use crate::sap::messages::{
     WinNotice,
 };
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Client {
     pub tokens: Arc<RwLock<Vec<Token>>>,
     pub blinded_tokens: Arc<RwLock<Vec<BlindedToken>>>,
@@ -1,6 +1,8 @@
 use challenge_bypass_ristretto::voprf::*;
 use serde_derive::{Deserialize, Serialize};
 
+pub use crate::redemption::RedeemResponse;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct IssueRequest {
     pub blinded_tokens: Vec<BlindedToken>,
@@ -13,16 +15,11 @@ pub struct IssueResponse {
     pub batch_proof: BatchDLEQProof,    
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct RedeemRequest {
     pub coins: Vec<UnblindedToken>
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RedeemResponse {
-    pub valid: bool,
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 pub struct WinNotice {
     pub price: u16,
@@ -5,7 +5,6 @@ pub mod messages;
 #[cfg(test)]
 mod test {
     use ark_serialize::*;
-    use bloomfilter::Bloom;    
     use challenge_bypass_ristretto::voprf::*;
     use parking_lot::RwLock;    
     use rand::rngs::OsRng;
@@ -14,11 +13,119 @@ mod test {
         fs::{create_dir_all, File},
         time::{Duration, Instant},
         sync::Arc,
+        thread,
     };    
 
+    use crate::billing::{BillingError, Earnings, Item, Ledger, Period, Scheme};
+    use crate::redemption::{CoinResult, Rejection};
     use crate::sap::client::Client;
     use crate::sap::server::Server;
     use crate::sap::messages::WinNotice;
+    use crate::spent::{BloomStore, Sharded};
+
+    const ADVERTISER: u64 = 1;
+    const PUBLISHER: u64 = 1;
+
+    // A server with a fresh signing key over the `spent` shards, neither
+    // billing nor keeping earnings
+    fn new_server(spent: Vec<BloomStore>) -> Server {
+        Server {
+            signing_key: SigningKey::random(&mut OsRng),
+            spent: Sharded::new(spent),
+            ledger: None,
+            earnings: None,
+        }
+    }
+
+    #[test]
+    fn concurrent_redeem() {
+        let server = Arc::new(new_server(vec![BloomStore::new(1000, 0.000001); 4]));
+        let mut client = Client::default();
+        let issue_request = client.issue_request(2);
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        let redeem_request = client.redeem_request(&WinNotice { price: 2 });
+
+        // Racing redemptions of the same tokens: exactly one is credited
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let server = server.clone();
+                let redeem_request = redeem_request.clone();
+                thread::spawn(move || server.redeem(PUBLISHER, redeem_request).valid)
+            })
+            .collect();
+        let credited = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&valid| valid)
+            .count();
+        assert_eq!(credited, 1);
+    }
+
+    #[test]
+    fn redeem_results() {
+        let earnings = Arc::new(Earnings::new());
+        let mut server = new_server(vec![BloomStore::new(1000, 0.000001)]);
+        server.earnings = Some(earnings.clone());
+        let mut client = Client::default();
+        let issue_request = client.issue_request(2);
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        let redeem_request = client.redeem_request(&WinNotice { price: 2 });
+
+        // Swapped tokens fail, repeated ones are double-spent, and neither
+        // request spends anything
+        let mut swapped = redeem_request.clone();
+        let (first, second) = swapped.coins.split_at_mut(1);
+        std::mem::swap(&mut first[0].W, &mut second[0].W);
+        let redeem_response = server.redeem(PUBLISHER, swapped);
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::BadProof); 2]);
+        let mut repeated = redeem_request.clone();
+        repeated.coins.push(redeem_request.coins[0].clone());
+        let redeem_response = server.redeem(PUBLISHER, repeated);
+        assert_eq!(redeem_response.coins,
+                   vec![CoinResult::Rejected(Rejection::Aborted),
+                        CoinResult::Rejected(Rejection::Aborted),
+                        CoinResult::Rejected(Rejection::DoubleSpent)]);
+
+        let redeem_response = server.redeem(PUBLISHER, redeem_request.clone());
+        assert_eq!(redeem_response.value, 2);
+        // Replayed by any publisher, the tokens are double-spent
+        let redeem_response = server.redeem(PUBLISHER + 1, redeem_request);
+        assert_eq!(redeem_response.coins, vec![CoinResult::Rejected(Rejection::DoubleSpent); 2]);
+        assert_eq!(earnings.balance(PUBLISHER + 1), 0);
+
+        // Only the accepted request was credited to the publisher
+        let history = earnings.history(PUBLISHER);
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].scheme, history[0].coins, history[0].value), (Scheme::Sap, 2, 2));
+    }
+
+    #[test]
+    fn billed_issuance() {
+        let ledger = Arc::new(Ledger::new());
+        let mut server = new_server(vec![BloomStore::new(1000, 0.000001)]);
+        server.ledger = Some(ledger.clone());
+        let mut client = Client::default();
+        ledger.open(7);
+        ledger.deposit(7, 2).unwrap();
+
+        // Each token costs one unit
+        let issue_request = client.issue_request(3);
+        assert!(matches!(server.issue(7, issue_request), Err(BillingError::InsufficientBalance(3))));
+        client.reset_state();
+        let issue_request = client.issue_request(2);
+        let issue_response = server.issue(7, issue_request).unwrap();
+        client.issue_process(issue_response).unwrap();
+        assert_eq!(ledger.balance(7).unwrap(), 0);
+        let statement = ledger.statement(7, Period { from: 0, to: u64::MAX }).unwrap();
+        assert_eq!(statement.entries.last().unwrap().item, Item::Tokens(2));
+
+        // Only the caller's own account is charged
+        client.reset_state();
+        let issue_request = client.issue_request(1);
+        assert!(matches!(server.issue(8, issue_request), Err(BillingError::UnknownAccount(8))));
+    }
 
     #[test]
     #[serial]    
@@ -86,7 +193,9 @@ mod test {
         let server = Server {
             signing_key,
             //spent_tokens: Arc::new(RwLock::new(Vec::new())),
-            bloom: Bloom::new_for_fp_rate(100000000, 0.000001),
+            spent: Sharded::new(vec![BloomStore::new(100000000, 0.000001)]),
+            ledger: None,
+            earnings: None,
         };
 
         const ITERS: u32 = 100;
@@ -99,14 +208,14 @@ mod test {
             for _ in 0..ITERS {
                 let issue_request = client.issue_request(*batch_size);
                 let start = Instant::now();
-                server.issue(issue_request);
+                server.issue(ADVERTISER, issue_request).unwrap();
                 duration += start.elapsed();
                 // Reset state
                 client.reset_state();                
             }
             let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
             let issue_request = client.issue_request(*batch_size);            
-            let issue_response = server.issue(issue_request);
+            let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
             let num_bytes = bincode::serialize(&issue_response).unwrap().len();
             comp_file.write_all(format!("{} {}\n", batch_size, avg_duration).as_bytes()).unwrap();
             comm_file.write_all(format!("{} {}\n", batch_size, num_bytes).as_bytes()).unwrap();
@@ -134,7 +243,9 @@ mod test {
         let server = Server {
             signing_key,
             //spent_tokens: Arc::new(RwLock::new(Vec::new())),
-            bloom: Bloom::new_for_fp_rate(100000000, 0.000001),
+            spent: Sharded::new(vec![BloomStore::new(100000000, 0.000001)]),
+            ledger: None,
+            earnings: None,
         };
 
         const ITERS: u32 = 100;
@@ -146,7 +257,7 @@ mod test {
             let mut duration = Duration::new(0, 0);
             for _ in 0..ITERS {
                 let issue_request = client.issue_request(*batch_size);
-                let issue_response = server.issue(issue_request);
+                let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
                 let start = Instant::now();
                 client.issue_process(issue_response).unwrap();
                 duration += start.elapsed();
@@ -179,11 +290,13 @@ mod test {
         let server = Server {
             signing_key,
             //spent_tokens: Arc::new(RwLock::new(Vec::new())),
-            bloom: Bloom::new_for_fp_rate(100000000, 0.000001),
+            spent: Sharded::new(vec![BloomStore::new(100000000, 0.000001)]),
+            ledger: None,
+            earnings: None,
         };
 
         let issue_request = client.issue_request(1);
-        let issue_response = server.issue(issue_request);
+        let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
         client.issue_process(issue_response).unwrap();
         let win_notice = WinNotice { price: 42 };        
         let mut redeem_request = client.redeem_request(&win_notice);
@@ -218,10 +331,12 @@ mod test {
             unblinded_tokens: Arc::new(RwLock::new(Vec::new())),
         };
 
-        let mut server = Server {
+        let server = Server {
             signing_key,
             //spent_tokens: Arc::new(RwLock::new(Vec::new())),
-            bloom: Bloom::new_for_fp_rate(100000000, 0.000001),
+            spent: Sharded::new(vec![BloomStore::new(100000000, 0.000001)]),
+            ledger: None,
+            earnings: None,
         };
 
         const ITERS: u32 = 100;
@@ -230,16 +345,16 @@ mod test {
             let mut duration = Duration::new(0, 0);
             for _ in 0..ITERS {
                 let issue_request = client.issue_request(hw);
-                let issue_response = server.issue(issue_request);
+                let issue_response = server.issue(ADVERTISER, issue_request).unwrap();
                 client.issue_process(issue_response).unwrap();
                 let win_notice = WinNotice { price: 42 };        
                 let redeem_request = client.redeem_request(&win_notice);
                 let start = Instant::now();
-                let redeem_response = server.redeem(redeem_request);
+                let redeem_response = server.redeem(PUBLISHER, redeem_request);
                 duration += start.elapsed();
                 // Reset state
                 assert!(redeem_response.valid);                                
-                server.bloom.clear();
+                server.spent.clear().unwrap();
                 client.reset_state();
             }
             let avg_duration = duration.checked_div(ITERS).unwrap().as_millis();
@@ -1,14 +1,13 @@
-use bloomfilter::Bloom;
 use challenge_bypass_ristretto::voprf::{
     BatchDLEQProof,
     SignedToken,
     SigningKey,
-    TokenPreimage,
 };
 //use parking_lot::RwLock;
 use rand::rngs::OsRng;
 use sha2::Sha512;
-//use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::Arc;
 
 use crate::sap::messages::{
     IssueRequest,
@@ -16,18 +15,27 @@ use crate::sap::messages::{
     RedeemRequest,
     RedeemResponse,
 };
+use crate::billing::{BillingError, Earnings, Item, Ledger, Scheme};
+use crate::redemption::Rejection;
+use crate::spent::{BloomStore, Sharded, SpentStore};
 
-#[derive(Clone)]
-pub struct Server {
+pub struct Server<S: SpentStore = BloomStore> {
     // XXX: Maybe move signing_key so it doesn't have to be cloned
     pub signing_key: SigningKey,
-    // XXX: Change to bloom filter in future
     //pub spent_tokens: Arc<RwLock<Vec<TokenPreimage>>>,
-    pub bloom: Bloom::<TokenPreimage>        
+    // Spent token preimages, keyed and sharded by their byte encoding
+    pub spent: Sharded<S>,
+    // Advertiser accounts charged per token issued, if billing is on
+    pub ledger: Option<Arc<Ledger>>,
+    // Publisher accounts credited for each accepted redemption, if kept
+    pub earnings: Option<Arc<Earnings>>,
 }
 
-impl Server {
-    pub fn issue(&self, req: IssueRequest) -> IssueResponse {
+impl<S: SpentStore> Server<S> {
+    /// Signs the blinded tokens of `req` for `advertiser`, charging one unit
+    /// per token to their account if the exchange bills. The caller vouches
+    /// for `advertiser`, which is never taken from the request itself.
+    pub fn issue(&self, advertiser: u64, req: IssueRequest) -> Result<IssueResponse, BillingError> {
         let mut rng = OsRng;
 
         let public_key = self.signing_key.public_key;
@@ -46,33 +54,66 @@ impl Server {
         )
         .unwrap();
 
-        IssueResponse {
+        if let Some(ledger) = &self.ledger {
+            ledger.charge(advertiser, Item::Tokens(signed_tokens.len() as u64))?;
+        }
+
+        Ok(IssueResponse {
             signed_tokens,
             public_key,
             batch_proof,
-        }
+        })
     }
 
-    pub fn redeem(&mut self, req: RedeemRequest) -> RedeemResponse {
-        let mut valid = true;
+    /// Redeems the tokens of `req` for `publisher`, crediting their value to
+    /// its earnings if the exchange keeps them. The caller vouches for
+    /// `publisher`, which is never taken from the request itself.
+    pub fn redeem(&self, publisher: u64, req: RedeemRequest) -> RedeemResponse {
+        let mut checks = Vec::with_capacity(req.coins.len());
+        let mut preimages = Vec::with_capacity(req.coins.len());
+        let mut seen = HashSet::new();
         
         for coin in req.coins.iter() {
-            // the exchange checks that the preimage has not previously been
-            // spent
-            if self.bloom.check_and_set(&coin.t) {
-                valid = false;
-                break;
-            };
+            let t = coin.t.to_bytes();
+            preimages.push(t);
+
+            // a preimage may only appear once per request
+            if !seen.insert(t) {
+                checks.push(Err(Rejection::DoubleSpent));
+                continue;
+            }
 
             // exchange derives the unblinded token using it's key and the clients token preimage
             let unblinded_token = self.signing_key.rederive_unblinded_token(&coin.t);
 
             if unblinded_token.W != coin.W {
-                valid = false;
-                break;
+                checks.push(Err(Rejection::BadProof));
+                continue;
             }
+            checks.push(Ok(1));
         }
-        
-        return RedeemResponse { valid }
+
+        // the exchange checks that no preimage has previously been spent,
+        // holding their shards so no concurrent request interleaves
+        let mut guards = self.spent.lock(&preimages);
+        for (check, t) in checks.iter_mut().zip(preimages.iter()) {
+            if check.is_ok() && guards.get(t).contains(t) {
+                *check = Err(Rejection::DoubleSpent);
+            }
+        }
+
+        let response = RedeemResponse::settle(checks);
+        if response.valid {
+            // a store failure records none of the preimages, so the request
+            // can be retried
+            let spends: Vec<(&[u8], Vec<u8>)> = preimages.iter().map(|t| (&t[..], t.to_vec())).collect();
+            if guards.insert_all(&spends).is_err() {
+                return RedeemResponse::reject(preimages.len(), Rejection::Aborted);
+            }
+            if let Some(earnings) = &self.earnings {
+                earnings.credit(publisher, Scheme::Sap, &response);
+            }
+        }
+        response
     }    
 }
@@ -0,0 +1,617 @@
+//! Double-spend state shared by the DAP and SAP servers.
+//!
+//! A server records the identifier of every token it accepts in a
+//! `SpentStore`. Identifiers are opaque byte strings, so one store works for
+//! DAP leaves and SAP token preimages alike. The stores trade exactness,
+//! memory and durability differently:
+//!
+//! - `ExactStore` keeps every identifier in memory and never errs.
+//! - `BloomStore` uses a fixed amount of memory but may report an unspent
+//!   token as spent at the configured false-positive rate.
+//! - `LogStore` is exact and appends every spend to a file, replaying it on
+//!   open so spent state survives a restart.
+//!
+//! Servers hold their store as a `Sharded` set of stores, each behind its own
+//! lock, so they can be shared between threads.
+use bloomfilter::Bloom;
+use parking_lot::{Mutex, MutexGuard};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+pub trait SpentStore {
+    /// Returns whether `item` has been spent.
+    fn contains(&self, item: &[u8]) -> bool;
+
+    /// Records `item` as spent.
+    fn insert(&mut self, item: &[u8]) -> io::Result<()>;
+
+    /// Records every item of `items` as spent, or none of them if it fails.
+    /// The default suits stores whose inserts cannot fail.
+    fn insert_all(&mut self, items: &[Vec<u8>]) -> io::Result<()> {
+        for item in items.iter() {
+            self.insert(item)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets every item of `items`, undoing an `insert_all` of items that
+    /// were not spent before it.
+    fn remove_all(&mut self, items: &[Vec<u8>]) -> io::Result<()>;
+
+    /// Forgets every spent item.
+    fn clear(&mut self) -> io::Result<()>;
+
+    /// Forgets every spent item starting with `prefix`.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> io::Result<()>;
+
+    /// Records `item` as spent and returns whether it already was.
+    fn check_and_set(&mut self, item: &[u8]) -> io::Result<bool> {
+        if self.contains(item) {
+            return Ok(true);
+        }
+        self.insert(item)?;
+        Ok(false)
+    }
+}
+
+/// In-memory store that remembers every spent item exactly.
+#[derive(Clone, Debug, Default)]
+pub struct ExactStore {
+    set: HashSet<Vec<u8>>,
+}
+
+impl ExactStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SpentStore for ExactStore {
+    fn contains(&self, item: &[u8]) -> bool {
+        self.set.contains(item)
+    }
+
+    fn insert(&mut self, item: &[u8]) -> io::Result<()> {
+        self.set.insert(item.to_vec());
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.set.clear();
+        Ok(())
+    }
+
+    fn remove_prefix(&mut self, prefix: &[u8]) -> io::Result<()> {
+        self.set.retain(|item| !item.starts_with(prefix));
+        Ok(())
+    }
+
+    fn remove_all(&mut self, items: &[Vec<u8>]) -> io::Result<()> {
+        for item in items.iter() {
+            self.set.remove(item);
+        }
+        Ok(())
+    }
+}
+
+/// Fixed-size store backed by a Bloom filter.
+///
+/// A Bloom filter cannot forget single items, so `remove_prefix` and
+/// `remove_all` keep them; they are only forgotten by `clear`. In particular,
+/// dropping a closed DAP epoch's spends by their prefix frees nothing, so a
+/// Bloom store must be sized for every epoch it will ever see. Its inserts
+/// never fail, so no batch ever needs undoing.
+///
+/// Each shard of a `Sharded` store is a filter of its own, so size each for
+/// its share of the expected spends.
+#[derive(Clone)]
+pub struct BloomStore {
+    bloom: Bloom<[u8]>,
+}
+
+impl BloomStore {
+    /// Sizes the filter for `items_count` spends at false-positive rate
+    /// `fp_rate`.
+    pub fn new(items_count: usize, fp_rate: f64) -> Self {
+        Self { bloom: Bloom::new_for_fp_rate(items_count, fp_rate) }
+    }
+}
+
+impl SpentStore for BloomStore {
+    fn contains(&self, item: &[u8]) -> bool {
+        self.bloom.check(item)
+    }
+
+    fn insert(&mut self, item: &[u8]) -> io::Result<()> {
+        self.bloom.set(item);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.bloom.clear();
+        Ok(())
+    }
+
+    fn remove_prefix(&mut self, _prefix: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_all(&mut self, _items: &[Vec<u8>]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check_and_set(&mut self, item: &[u8]) -> io::Result<bool> {
+        Ok(self.bloom.check_and_set(item))
+    }
+}
+
+/// Exact store that appends each spend to a log file.
+///
+/// Each record is the item's length as a little-endian `u32` followed by the
+/// item. A batch from `insert_all` is one record whose length has the top
+/// bit set and whose body is the records of its items, so it is replayed in
+/// full or not at all. A record cut short by a crash is dropped when the log
+/// is reopened.
+pub struct LogStore {
+    path: PathBuf,
+    file: File,
+    set: HashSet<Vec<u8>>,
+}
+
+impl LogStore {
+    /// Opens the log at `path`, creating it if needed, and replays the
+    /// spends already in it.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut log = vec![];
+        file.read_to_end(&mut log)?;
+
+        let mut set = HashSet::new();
+        let offset = replay(&log, &mut set);
+        // Drop a partial record so later appends stay aligned
+        if offset != log.len() {
+            file.set_len(offset as u64)?;
+        }
+
+        Ok(Self { path, file, set })
+    }
+
+    // Writes the items of `kept` to a fresh log and swaps it in, so a crash
+    // leaves either the old log or the new one
+    fn rewrite(&mut self, kept: HashSet<Vec<u8>>) -> io::Result<()> {
+        let tmp = self.path.with_extension("compact");
+        let mut file = File::create(&tmp)?;
+        for item in kept.iter() {
+            write_record(&mut file, 0, item)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.set = kept;
+        Ok(())
+    }
+}
+
+// Top bit of the length of a record holding a batch of records
+const BATCH: u32 = 1 << 31;
+
+// Reads the items of the records in `log` into `set`, and returns the length
+// of the records that are complete
+fn replay(log: &[u8], set: &mut HashSet<Vec<u8>>) -> usize {
+    let mut offset = 0;
+    while offset + 4 <= log.len() {
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&log[offset..offset + 4]);
+        let header = u32::from_le_bytes(len_bytes);
+        let len = (header & !BATCH) as usize;
+        if offset + 4 + len > log.len() {
+            break;
+        }
+        let body = &log[offset + 4..offset + 4 + len];
+        if header & BATCH != 0 {
+            replay(body, set);
+        } else {
+            set.insert(body.to_vec());
+        }
+        offset += 4 + len;
+    }
+    offset
+}
+
+fn write_record<W: Write>(out: &mut W, flags: u32, body: &[u8]) -> io::Result<()> {
+    let len: u32 = body.len()
+        .try_into()
+        .ok()
+        .filter(|len| len & BATCH == 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Item too long"))?;
+    let mut record = Vec::with_capacity(4 + body.len());
+    record.extend_from_slice(&(len | flags).to_le_bytes());
+    record.extend_from_slice(body);
+    out.write_all(&record)
+}
+
+impl SpentStore for LogStore {
+    fn contains(&self, item: &[u8]) -> bool {
+        self.set.contains(item)
+    }
+
+    fn insert(&mut self, item: &[u8]) -> io::Result<()> {
+        if self.set.contains(item) {
+            return Ok(());
+        }
+        write_record(&mut self.file, 0, item)?;
+        self.file.sync_data()?;
+        self.set.insert(item.to_vec());
+        Ok(())
+    }
+
+    fn insert_all(&mut self, items: &[Vec<u8>]) -> io::Result<()> {
+        let new: Vec<&Vec<u8>> = items.iter().filter(|item| !self.set.contains(*item)).collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        let mut batch = vec![];
+        for item in new.iter() {
+            write_record(&mut batch, 0, item)?;
+        }
+        let len = self.file.metadata()?.len();
+        let written = write_record(&mut self.file, BATCH, &batch).and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // Drop whatever part of the batch made it out
+            self.file.set_len(len)?;
+            return Err(e);
+        }
+        self.set.extend(new.into_iter().cloned());
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.set.clear();
+        Ok(())
+    }
+
+    fn remove_prefix(&mut self, prefix: &[u8]) -> io::Result<()> {
+        if !self.set.iter().any(|item| item.starts_with(prefix)) {
+            return Ok(());
+        }
+        let kept: HashSet<Vec<u8>> = self.set
+            .iter()
+            .filter(|item| !item.starts_with(prefix))
+            .cloned()
+            .collect();
+        self.rewrite(kept)
+    }
+
+    fn remove_all(&mut self, items: &[Vec<u8>]) -> io::Result<()> {
+        let removed: HashSet<&[u8]> = items.iter().map(|item| &item[..]).collect();
+        if !self.set.iter().any(|item| removed.contains(&item[..])) {
+            return Ok(());
+        }
+        let kept: HashSet<Vec<u8>> = self.set
+            .iter()
+            .filter(|item| !removed.contains(&item[..]))
+            .cloned()
+            .collect();
+        self.rewrite(kept)
+    }
+}
+
+/// Number of shards a server's store is split into by default.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// A store split into shards, each behind its own lock.
+///
+/// Items are placed by a key the caller chooses, so items that must be
+/// checked together (such as the nodes of one DAP wallet) can share a shard.
+pub struct Sharded<S> {
+    shards: Vec<Mutex<S>>,
+}
+
+impl<S: SpentStore> Sharded<S> {
+    /// Panics if `shards` is empty.
+    pub fn new(shards: Vec<S>) -> Self {
+        assert!(!shards.is_empty(), "A sharded store needs at least one shard");
+        Self { shards: shards.into_iter().map(Mutex::new).collect() }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the index of the shard holding items placed by `key`.
+    pub fn shard(&self, key: &[u8]) -> usize {
+        // A stable hash, so a persistent shard is found again after a restart
+        let hash = blake3::hash(key);
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&hash.as_bytes()[..8]);
+        (u64::from_le_bytes(bytes) % self.shards.len() as u64) as usize
+    }
+
+    /// Locks the shards holding items placed by each of `keys`. Shards are
+    /// locked in index order, so concurrent callers cannot deadlock.
+    pub fn lock<K: AsRef<[u8]>>(&self, keys: &[K]) -> Guards<'_, S> {
+        let indices: BTreeSet<usize> = keys
+            .iter()
+            .map(|key| self.shard(key.as_ref()))
+            .collect();
+        let guards = indices
+            .into_iter()
+            .map(|i| (i, self.shards[i].lock()))
+            .collect();
+        Guards { sharded: self, guards }
+    }
+
+    /// Forgets every spent item in every shard.
+    pub fn clear(&self) -> io::Result<()> {
+        for shard in self.shards.iter() {
+            shard.lock().clear()?;
+        }
+        Ok(())
+    }
+
+    /// Forgets every spent item starting with `prefix`, in every shard.
+    pub fn remove_prefix(&self, prefix: &[u8]) -> io::Result<()> {
+        for shard in self.shards.iter() {
+            shard.lock().remove_prefix(prefix)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: SpentStore + Default> Sharded<S> {
+    /// Splits the store into `n` default stores.
+    pub fn with_shards(n: usize) -> Self {
+        Self::new((0..n).map(|_| S::default()).collect())
+    }
+}
+
+/// Shards of a `Sharded` store locked by `Sharded::lock`.
+pub struct Guards<'a, S> {
+    sharded: &'a Sharded<S>,
+    guards: BTreeMap<usize, MutexGuard<'a, S>>,
+}
+
+impl<S: SpentStore> Guards<'_, S> {
+    /// Returns the shard for `key`. Panics if it was not locked.
+    pub fn get(&self, key: &[u8]) -> &S {
+        &self.guards[&self.sharded.shard(key)]
+    }
+
+    /// Returns the shard for `key` mutably. Panics if it was not locked.
+    pub fn get_mut(&mut self, key: &[u8]) -> &mut S {
+        let i = self.sharded.shard(key);
+        self.guards.get_mut(&i).expect("Shard not locked")
+    }
+
+    /// Records each `(key, item)` of `items` in the shard for `key`, in
+    /// every shard or none if one fails. Panics if a shard was not locked.
+    pub fn insert_all<K: AsRef<[u8]>>(&mut self, items: &[(K, Vec<u8>)]) -> io::Result<()> {
+        // Only items not spent yet go in a shard's batch, so undoing it
+        // can't forget an earlier spend
+        let mut batches: BTreeMap<usize, Vec<Vec<u8>>> = BTreeMap::new();
+        for (key, item) in items.iter() {
+            let i = self.sharded.shard(key.as_ref());
+            let batch = batches.entry(i).or_default();
+            if !self.guards[&i].contains(item) && !batch.contains(item) {
+                batch.push(item.clone());
+            }
+        }
+
+        let mut done = vec![];
+        for (i, batch) in batches {
+            let shard = self.guards.get_mut(&i).expect("Shard not locked");
+            if let Err(e) = shard.insert_all(&batch) {
+                for (j, batch) in done.iter() {
+                    self.guards.get_mut(j).expect("Shard not locked").remove_all(batch)?;
+                }
+                return Err(e);
+            }
+            done.push((i, batch));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        fs::{create_dir_all, remove_dir_all, OpenOptions},
+        io::Write,
+        process,
+        sync::Arc,
+        thread,
+    };
+
+    use crate::spent::*;
+
+    fn exercise<S: SpentStore>(store: &mut S) {
+        assert!(!store.contains(b"a"));
+        assert!(!store.check_and_set(b"a").unwrap());
+        assert!(store.check_and_set(b"a").unwrap());
+        store.insert(b"b").unwrap();
+        assert!(store.contains(b"b"));
+        store.clear().unwrap();
+        assert!(!store.contains(b"a"));
+        assert!(!store.contains(b"b"));
+    }
+
+    #[test]
+    fn exact_store() {
+        let mut store = ExactStore::new();
+        exercise(&mut store);
+        store.insert(b"1a").unwrap();
+        store.insert(b"2a").unwrap();
+        store.remove_prefix(b"1").unwrap();
+        assert!(!store.contains(b"1a"));
+        assert!(store.contains(b"2a"));
+        store.insert_all(&[b"3a".to_vec(), b"3b".to_vec()]).unwrap();
+        store.remove_all(&[b"3a".to_vec()]).unwrap();
+        assert!(!store.contains(b"3a"));
+        assert!(store.contains(b"3b"));
+    }
+
+    #[test]
+    fn bloom_store() {
+        exercise(&mut BloomStore::new(1000, 0.000001));
+    }
+
+    #[test]
+    fn log_store() {
+        // A directory of this test run's own, removed once it passes
+        let dir = env::temp_dir().join(format!("divtokens_spent_{}", process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("spent.log");
+
+        let mut store = LogStore::open(&path).unwrap();
+        exercise(&mut store);
+        store.insert(b"a").unwrap();
+        store.insert(b"bc").unwrap();
+        drop(store);
+
+        // Spends survive a reopen, and a torn record is dropped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[9, 0, 0, 0, b'x']).unwrap();
+        drop(file);
+        let mut store = LogStore::open(&path).unwrap();
+        assert!(store.contains(b"a"));
+        assert!(store.contains(b"bc"));
+        assert!(!store.contains(b"x"));
+        store.insert(b"d").unwrap();
+        drop(store);
+
+        let mut store = LogStore::open(&path).unwrap();
+        assert!(store.contains(b"d"));
+
+        // Removed items stay removed after a reopen
+        store.remove_prefix(b"b").unwrap();
+        assert!(!store.contains(b"bc"));
+        store.insert(b"e").unwrap();
+        drop(store);
+        let mut store = LogStore::open(&path).unwrap();
+        assert!(store.contains(b"a"));
+        assert!(!store.contains(b"bc"));
+        assert!(store.contains(b"e"));
+
+        // A batch is replayed in full, and a torn one not at all
+        store.insert_all(&[b"f".to_vec(), b"gh".to_vec()]).unwrap();
+        store.remove_all(&[b"e".to_vec()]).unwrap();
+        drop(store);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[10, 0, 0, 0x80, 1, 0, 0, 0, b'y']).unwrap();
+        drop(file);
+        let store = LogStore::open(&path).unwrap();
+        assert!(store.contains(b"f"));
+        assert!(store.contains(b"gh"));
+        assert!(!store.contains(b"e"));
+        assert!(!store.contains(b"y"));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sharded_store() {
+        let sharded = Sharded::<ExactStore>::with_shards(4);
+        let keys: Vec<[u8; 1]> = (0..16u8).map(|i| [i]).collect();
+        {
+            let mut guards = sharded.lock(&keys);
+            for key in keys.iter() {
+                guards.get_mut(key).insert(key).unwrap();
+            }
+        }
+        let guards = sharded.lock(&keys[..1]);
+        assert!(guards.get(&keys[0]).contains(&keys[0]));
+        drop(guards);
+        sharded.clear().unwrap();
+        assert!(!sharded.lock(&keys[..1]).get(&keys[0]).contains(&keys[0]));
+
+        // Concurrent spends of one item: exactly one wins
+        let sharded = Arc::new(sharded);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let sharded = sharded.clone();
+                thread::spawn(move || {
+                    !sharded.lock(&[b"a"]).get_mut(b"a").check_and_set(b"a").unwrap()
+                })
+            })
+            .collect();
+        let wins = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&won| won)
+            .count();
+        assert_eq!(wins, 1);
+    }
+
+    // Exact store whose batches fail once `fail` is set
+    #[derive(Default)]
+    struct FailingStore {
+        inner: ExactStore,
+        fail: bool,
+    }
+
+    impl SpentStore for FailingStore {
+        fn contains(&self, item: &[u8]) -> bool {
+            self.inner.contains(item)
+        }
+
+        fn insert(&mut self, item: &[u8]) -> io::Result<()> {
+            self.inner.insert(item)
+        }
+
+        fn insert_all(&mut self, items: &[Vec<u8>]) -> io::Result<()> {
+            if self.fail {
+                return Err(io::Error::new(io::ErrorKind::Other, "Full"));
+            }
+            self.inner.insert_all(items)
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            self.inner.clear()
+        }
+
+        fn remove_prefix(&mut self, prefix: &[u8]) -> io::Result<()> {
+            self.inner.remove_prefix(prefix)
+        }
+
+        fn remove_all(&mut self, items: &[Vec<u8>]) -> io::Result<()> {
+            self.inner.remove_all(items)
+        }
+    }
+
+    #[test]
+    fn sharded_insert_all() {
+        let sharded = Sharded::<FailingStore>::with_shards(2);
+        let a = b"a".to_vec();
+        let b = (0..=u8::MAX)
+            .map(|i| vec![i])
+            .find(|key| sharded.shard(key) != sharded.shard(&a))
+            .unwrap();
+        let keys = [a.clone(), b.clone()];
+
+        // An item spent earlier survives the undo of a failed batch
+        let mut guards = sharded.lock(&keys);
+        guards.insert_all(&[(&a, b"old".to_vec())]).unwrap();
+        guards.get_mut(&b).fail = true;
+        let batch = [(&a, b"old".to_vec()), (&a, b"new".to_vec()), (&b, b"new".to_vec())];
+        assert!(guards.insert_all(&batch).is_err());
+        assert!(guards.get(&a).contains(b"old"));
+        assert!(!guards.get(&a).contains(b"new"));
+
+        guards.get_mut(&b).fail = false;
+        guards.insert_all(&batch).unwrap();
+        assert!(guards.get(&a).contains(b"new"));
+        assert!(guards.get(&b).contains(b"new"));
+    }
+}